@@ -0,0 +1,392 @@
+//! Append-only checkpoint files for long velocity runs.
+//!
+//! A checkpoint is a JSON Lines file. The first line records the grid geometry and the run's
+//! settings, and every following line records the result of one cell, written as soon as the cell
+//! is finished. If a run is interrupted it can be restarted with the same checkpoint and settings,
+//! and cells that already have a result are skipped.
+
+use chrono::{DateTime, Utc};
+use failure::Error;
+use geometry::Geometry;
+use quadtree::Address;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use velocities::{Failure, Unit, Velocity, VelocityFile};
+
+/// The checkpoint was written for a grid with a different geometry.
+#[derive(Debug, Fail)]
+#[fail(display = "The checkpoint was written for a different grid geometry")]
+pub struct GeometryMismatch {}
+
+/// The checkpoint was written with a different random seed.
+#[derive(Debug, Fail)]
+#[fail(display = "The checkpoint was written with seed {}, not {}", _0, _1)]
+pub struct SeedMismatch(u64, u64);
+
+/// The checkpoint was written with different run settings.
+#[derive(Debug, Fail)]
+#[fail(display = "The checkpoint was written with settings {:?}, not {:?}", _0, _1)]
+pub struct SettingsMismatch(Settings, Settings);
+
+/// The checkpoint file does not start with a header.
+#[derive(Debug, Fail)]
+#[fail(display = "The checkpoint file is missing its header")]
+pub struct MissingHeader {}
+
+/// An open checkpoint file.
+#[derive(Debug)]
+pub struct Checkpoint {
+    completed: Mutex<BTreeMap<Address, Record>>,
+    writer: Mutex<BufWriter<File>>,
+}
+
+/// The settings of a velocity run, other than its geometry and seed, that change its results.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    /// The number of bootstrap resamples per cell.
+    pub bootstrap: usize,
+
+    /// The date and time of the velocities.
+    pub datetime: DateTime<Utc>,
+
+    /// Whether growing cells may merge neighbors that already have enough points.
+    pub grow_healthy: bool,
+
+    /// The registration method, as passed to `registration::from_name`.
+    pub method: String,
+
+    /// The minimum number of points in a cell.
+    pub min_points: usize,
+
+    /// The number of times the grid was grown.
+    pub ngrow: usize,
+
+    /// The distance within which a registered point counts as overlapping an after point.
+    pub overlap_tolerance: f64,
+
+    /// A fingerprint of the prior velocities, if the run was warm-started.
+    pub prior: Option<u64>,
+
+    /// The minimum grid size and the gradient used for refinement, if cells are refined.
+    pub refinement: Option<(f64, f64)>,
+
+    /// The time between the before and after scans, in seconds.
+    pub separation: f64,
+
+    /// The unit of the velocities.
+    pub unit: Unit,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    geometry: Geometry,
+    #[serde(default)]
+    seed: u64,
+    #[serde(default)]
+    settings: Option<Settings>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Record {
+    address: Address,
+    #[serde(default)]
+    failure: Option<Failure>,
+    #[serde(default)]
+    velocity: Option<Velocity>,
+}
+
+impl Checkpoint {
+    /// Opens a checkpoint file for a grid, creating it if it does not exist.
+    ///
+    /// Results already in the file are loaded so their cells can be skipped. Lines that can't be
+    /// read, such as a line cut short when the previous run was killed, are ignored. An existing
+    /// file must have been written with the same geometry, seed, and settings. Files written
+    /// before settings were recorded are only checked for their geometry and seed.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        geometry: Geometry,
+        seed: u64,
+        settings: &Settings,
+    ) -> Result<Checkpoint, Error> {
+        use serde_json;
+        use std::fs::OpenOptions;
+
+        let completed = if path.as_ref().exists() {
+            let (header, records) = read(&path)?;
+            if header.geometry != round_trip(&geometry)? {
+                return Err(GeometryMismatch {}.into());
+            }
+            if header.seed != seed {
+                return Err(SeedMismatch(header.seed, seed).into());
+            }
+            match header.settings {
+                Some(recorded) => {
+                    let settings = round_trip(settings)?;
+                    if recorded != settings {
+                        return Err(SettingsMismatch(recorded, settings).into());
+                    }
+                }
+                None => {
+                    warn!(
+                        "{} does not record its settings, so they can't be checked",
+                        path.as_ref().display()
+                    )
+                }
+            }
+            info!(
+                "Resuming from {} with {} completed cells",
+                path.as_ref().display(),
+                records.len()
+            );
+            records
+        } else {
+            let mut file = File::create(&path)?;
+            serde_json::to_writer(
+                &mut file,
+                &Header {
+                    geometry: geometry,
+                    seed: seed,
+                    settings: Some(settings.clone()),
+                },
+            )?;
+            file.write_all(b"\n")?;
+            BTreeMap::new()
+        };
+        let mut file = OpenOptions::new().read(true).append(true).open(&path)?;
+        if !ends_with_newline(&mut file)? {
+            file.write_all(b"\n")?;
+        }
+        Ok(Checkpoint {
+            completed: Mutex::new(completed),
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Takes the result recorded for a cell in an earlier run, if there is one.
+    pub fn take(&self, address: &Address) -> Option<Result<Velocity, Failure>> {
+        let record = self.completed.lock().unwrap().remove(address);
+        record.and_then(Record::into_result)
+    }
+
+    /// Appends the result of a cell to the checkpoint file.
+    ///
+    /// Each line is flushed as soon as it is written, so a result is never lost once it has been
+    /// recorded.
+    pub fn record(
+        &self,
+        address: Address,
+        result: &Result<Velocity, Failure>,
+    ) -> Result<(), Error> {
+        use serde_json;
+
+        let line = match *result {
+            Ok(ref velocity) => json!({"address": address, "velocity": velocity}),
+            Err(ref failure) => json!({"address": address, "failure": failure}),
+        };
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, &line)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Consolidates a checkpoint file into a velocity file.
+///
+/// If a cell was refined, only its children are kept. This can be used on the checkpoint of an
+/// unfinished run, in which case the velocity file will have holes where cells were not yet
+/// calculated. Cells culled for having too few points are never calculated, so they are not in
+/// the checkpoint.
+pub fn consolidate<P: AsRef<Path>>(path: P) -> Result<VelocityFile, Error> {
+    let (header, records) = read(path)?;
+    let leaves = records
+        .keys()
+        .filter(|address| {
+            address.level < 0 ||
+                !address.children().iter().any(
+                    |child| records.contains_key(child),
+                )
+        })
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let results = records
+        .into_iter()
+        .filter(|&(address, _)| leaves.contains(&address))
+        .filter_map(|(_, record)| record.into_result())
+        .collect();
+    let mut velocity_file = VelocityFile::from_results(header.geometry, results);
+    velocity_file.seed = Some(header.seed);
+    Ok(velocity_file)
+}
+
+impl Record {
+    fn into_result(self) -> Option<Result<Velocity, Failure>> {
+        match (self.velocity, self.failure) {
+            (Some(velocity), _) => Some(Ok(velocity)),
+            (None, Some(failure)) => Some(Err(failure)),
+            (None, None) => None,
+        }
+    }
+}
+
+fn read<P: AsRef<Path>>(path: P) -> Result<(Header, BTreeMap<Address, Record>), Error> {
+    use serde_json;
+    use std::io::{BufRead, BufReader};
+
+    let mut lines = BufReader::new(File::open(&path)?).lines();
+    let header = match lines.next() {
+        Some(line) => serde_json::from_str::<Header>(&line?)?,
+        None => return Err(MissingHeader {}.into()),
+    };
+    let mut records = BTreeMap::new();
+    let mut skipped = 0;
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Record>(&line) {
+            Ok(ref record) if record.velocity.is_none() && record.failure.is_none() => {
+                skipped += 1
+            }
+            Ok(record) => {
+                records.insert(record.address, record);
+            }
+            Err(_) => skipped += 1,
+        }
+    }
+    if skipped > 0 {
+        warn!(
+            "Skipped {} unreadable lines in {}",
+            skipped,
+            path.as_ref().display()
+        );
+    }
+    Ok((header, records))
+}
+
+/// Passes a value through JSON, so it can be compared exactly with a value read from a header.
+fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> Result<T, Error> {
+    use serde_json;
+
+    serde_json::from_str(&serde_json::to_string(value)?).map_err(Error::from)
+}
+
+fn ends_with_newline(file: &mut File) -> Result<bool, Error> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    if file.metadata()?.len() == 0 {
+        return Ok(true);
+    }
+    let mut byte = [0u8];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut byte)?;
+    Ok(byte[0] == b'\n')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn settings() -> Settings {
+        Settings {
+            bootstrap: 0,
+            datetime: "2017-06-01T12:00:00Z".parse().unwrap(),
+            grow_healthy: false,
+            method: "cpd".to_string(),
+            min_points: 250,
+            ngrow: 1,
+            overlap_tolerance: 1.,
+            prior: None,
+            refinement: None,
+            separation: 3600.,
+            unit: Unit::MetersPerHour,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        use std::env;
+        use std::fs;
+
+        let path = env::temp_dir().join("ape-checkpoint-round-trip.jsonl");
+        let _ = fs::remove_file(&path);
+        let geometry = Geometry::new(10.).unwrap();
+        let velocity = serde_json::from_value::<Velocity>(json!({
+            "after_points": 300,
+            "before_points": 300,
+            "center_of_gravity": {"x": 5., "y": 5., "z": 0.},
+            "datetime": "2017-06-01T12:00:00Z",
+            "grid_size": 10.,
+            "iterations": 12,
+            "velocity": {"x": 1., "y": 0., "z": 0.},
+            "x": 0.,
+            "y": 0.
+        })).unwrap();
+        let failure = serde_json::from_value::<Failure>(json!({
+            "after_points": 10,
+            "before_points": 10,
+            "bounds": {"min_x": 10., "min_y": 0., "max_x": 20., "max_y": 10.},
+            "col": 1,
+            "grid_size": 10.,
+            "kind": "registration",
+            "level": 0,
+            "message": "singular matrix",
+            "row": 0
+        })).unwrap();
+        {
+            let checkpoint = Checkpoint::open(&path, geometry, 42, &settings()).unwrap();
+            checkpoint.record(velocity.address(), &Ok(velocity.clone())).unwrap();
+            checkpoint.record(failure.address(), &Err(failure.clone())).unwrap();
+        }
+
+        let checkpoint = Checkpoint::open(&path, geometry, 42, &settings()).unwrap();
+        assert!(checkpoint.take(&velocity.address()).unwrap().is_ok());
+        assert!(checkpoint.take(&failure.address()).unwrap().is_err());
+        assert!(checkpoint.take(&velocity.address()).is_none());
+
+        let mut other = settings();
+        other.method = "icp".to_string();
+        assert!(
+            Checkpoint::open(&path, geometry, 42, &other)
+                .unwrap_err()
+                .downcast::<SettingsMismatch>()
+                .is_ok()
+        );
+        assert!(
+            Checkpoint::open(&path, geometry, 43, &settings())
+                .unwrap_err()
+                .downcast::<SeedMismatch>()
+                .is_ok()
+        );
+
+        let velocity_file = consolidate(&path).unwrap();
+        assert_eq!(1, velocity_file.velocities.len());
+        assert_eq!(1, velocity_file.failures.len());
+        assert_eq!(Some(42), velocity_file.seed);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn inexact_floats() {
+        use std::env;
+        use std::fs;
+
+        let path = env::temp_dir().join("ape-checkpoint-inexact-floats.jsonl");
+        let _ = fs::remove_file(&path);
+        let geometry = Geometry::new(0.1 + 0.2).unwrap().origin(535_508.04 + 0.1, -0.1 - 0.7);
+        let mut settings = settings();
+        settings.overlap_tolerance = 1. / 3.;
+        settings.refinement = Some((2.5, 0.1 + 0.2));
+        settings.separation = 86_400. * 1.1;
+        Checkpoint::open(&path, geometry, 42, &settings).unwrap();
+        Checkpoint::open(&path, geometry, 42, &settings).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
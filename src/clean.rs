@@ -0,0 +1,247 @@
+//! Spatial outlier detection for velocity fields.
+//!
+//! Each velocity is compared with its neighborhood, the velocities whose cell centers are within
+//! some multiple of the cell's size. Outliers are flagged, not removed, so the data can still be
+//! inspected or used with a different cleaning later.
+
+use Vector;
+use kdtree::KdTree;
+use stats::median;
+use std::fmt;
+use velocities::VelocityFile;
+
+/// The reason a velocity was flagged as an outlier.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Flag {
+    /// The velocity failed the normalized median test.
+    Median,
+
+    /// The velocity's direction does not agree with its neighbors' directions.
+    Direction,
+
+    /// The velocity's magnitude is far from its neighbors' magnitudes.
+    Magnitude,
+}
+
+/// Flags velocities that differ strongly from their neighborhood.
+#[derive(Clone, Copy, Debug)]
+pub struct Cleaner {
+    coherence: Option<f64>,
+    epsilon: f64,
+    median: Option<f64>,
+    min_neighbors: usize,
+    neighborhood: f64,
+    z_score: Option<f64>,
+}
+
+impl Cleaner {
+    /// Creates a new cleaner with the default tests.
+    ///
+    /// By default the normalized median threshold is 2, the direction coherence threshold is 0.5,
+    /// and the magnitude z-score threshold is 3.
+    pub fn new() -> Cleaner {
+        Cleaner {
+            coherence: Some(0.5),
+            epsilon: 0.,
+            median: Some(2.),
+            min_neighbors: 3,
+            neighborhood: 1.5,
+            z_score: Some(3.),
+        }
+    }
+
+    /// Sets the threshold of the normalized median test, or disables it.
+    ///
+    /// This is the test commonly used for particle image velocimetry (Westerweel and Scarano,
+    /// 2005). Each horizontal component's distance from the neighbors' median is divided by the
+    /// median of the neighbors' own distances from that median, plus `epsilon`.
+    pub fn median<T: Into<Option<f64>>>(mut self, threshold: T) -> Cleaner {
+        self.median = threshold.into();
+        self
+    }
+
+    /// Sets the noise level added to the normalized median test's denominator.
+    ///
+    /// This keeps very uniform neighborhoods from flagging velocities that differ by less than
+    /// the measurement noise. It is in the units of the velocities.
+    pub fn epsilon(mut self, epsilon: f64) -> Cleaner {
+        self.epsilon = epsilon;
+        self
+    }
+
+    /// Sets the minimum direction coherence, or disables the direction test.
+    ///
+    /// The coherence is the mean cosine of the horizontal angles between a velocity and its
+    /// neighbors, so it is one if every neighbor points the same way and negative if most point
+    /// the opposite way.
+    pub fn coherence<T: Into<Option<f64>>>(mut self, threshold: T) -> Cleaner {
+        self.coherence = threshold.into();
+        self
+    }
+
+    /// Sets the maximum magnitude z-score, or disables the magnitude test.
+    pub fn z_score<T: Into<Option<f64>>>(mut self, threshold: T) -> Cleaner {
+        self.z_score = threshold.into();
+        self
+    }
+
+    /// Sets the size of the neighborhood, as a multiple of each cell's size.
+    ///
+    /// The default, 1.5, includes the eight cells around a cell in a regular grid.
+    pub fn neighborhood(mut self, neighborhood: f64) -> Cleaner {
+        self.neighborhood = neighborhood;
+        self
+    }
+
+    /// Sets the minimum number of neighbors needed to test a velocity.
+    ///
+    /// Velocities with fewer neighbors are never flagged.
+    pub fn min_neighbors(mut self, min_neighbors: usize) -> Cleaner {
+        self.min_neighbors = min_neighbors;
+        self
+    }
+
+    /// Flags the outliers in a velocity file, replacing any existing flags.
+    ///
+    /// Returns the number of flagged velocities.
+    pub fn clean(&self, velocity_file: &mut VelocityFile) -> usize {
+        let centers = velocity_file
+            .velocities
+            .iter()
+            .map(|v| {
+                let (x, y) = v.bounds.center();
+                [x, y, 0.]
+            })
+            .collect::<Vec<_>>();
+        if centers.is_empty() {
+            return 0;
+        }
+        let tree = KdTree::new(centers.clone(), 2);
+        let flags = velocity_file
+            .velocities
+            .iter()
+            .enumerate()
+            .map(|(i, velocity)| {
+                let neighbors = tree.within(&centers[i], self.neighborhood * velocity.grid_size)
+                    .into_iter()
+                    .filter(|&(j, _)| j != i)
+                    .map(|(j, _)| velocity_file.velocities[j].velocity)
+                    .collect::<Vec<_>>();
+                self.flags(velocity.velocity, &neighbors)
+            })
+            .collect::<Vec<_>>();
+        let mut count = 0;
+        for (velocity, flags) in velocity_file.velocities.iter_mut().zip(flags) {
+            if !flags.is_empty() {
+                count += 1;
+            }
+            velocity.flags = flags;
+        }
+        count
+    }
+
+    fn flags(&self, velocity: Vector, neighbors: &[Vector]) -> Vec<Flag> {
+        let mut flags = Vec::new();
+        if neighbors.len() < self.min_neighbors || neighbors.is_empty() {
+            return flags;
+        }
+        if let Some(threshold) = self.median {
+            let x = neighbors.iter().map(|v| v.x).collect::<Vec<_>>();
+            let y = neighbors.iter().map(|v| v.y).collect::<Vec<_>>();
+            let rx = normalized_median_residual(velocity.x, &x, self.epsilon);
+            let ry = normalized_median_residual(velocity.y, &y, self.epsilon);
+            if (rx * rx + ry * ry).sqrt() > threshold {
+                flags.push(Flag::Median);
+            }
+        }
+        if let Some(threshold) = self.coherence {
+            if let Some(coherence) = coherence(velocity, neighbors) {
+                if coherence < threshold {
+                    flags.push(Flag::Direction);
+                }
+            }
+        }
+        if let Some(threshold) = self.z_score {
+            let magnitudes = neighbors.iter().map(|v| v.xy()).collect::<Vec<_>>();
+            let n = magnitudes.len() as f64;
+            let mean = magnitudes.iter().sum::<f64>() / n;
+            if magnitudes.len() > 1 {
+                let std = (magnitudes.iter().map(|m| (m - mean).powi(2)).sum::<f64>() /
+                               (n - 1.))
+                    .sqrt();
+                if std > 0. && ((velocity.xy() - mean) / std).abs() > threshold {
+                    flags.push(Flag::Magnitude);
+                }
+            }
+        }
+        flags
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Flag::Median => write!(f, "median"),
+            Flag::Direction => write!(f, "direction"),
+            Flag::Magnitude => write!(f, "magnitude"),
+        }
+    }
+}
+
+impl Default for Cleaner {
+    fn default() -> Cleaner {
+        Cleaner::new()
+    }
+}
+
+/// Returns the normalized median residual of a value with respect to its neighbors.
+///
+/// If the neighbors' residuals are all zero and `epsilon` is zero, any difference from the median
+/// is infinitely large. With no neighbors the residual is zero.
+///
+/// # Examples
+///
+/// ```
+/// use ape::clean::normalized_median_residual;
+/// let neighbors = [1., 2., 3., 4., 5.];
+/// assert_eq!(0., normalized_median_residual(3., &neighbors, 0.));
+/// assert_eq!(7., normalized_median_residual(10., &neighbors, 0.));
+/// ```
+pub fn normalized_median_residual(value: f64, neighbors: &[f64], epsilon: f64) -> f64 {
+    let center = match median(neighbors) {
+        Some(center) => center,
+        None => return 0.,
+    };
+    let residuals = neighbors
+        .iter()
+        .map(|n| (n - center).abs())
+        .collect::<Vec<_>>();
+    let difference = (value - center).abs();
+    if difference == 0. {
+        0.
+    } else {
+        difference / (median(&residuals).unwrap() + epsilon)
+    }
+}
+
+/// Returns the mean cosine of the horizontal angles between a vector and its neighbors.
+///
+/// Vectors without a horizontal component have no direction, so they are skipped. Returns `None`
+/// if the vector or all of its neighbors have no direction.
+fn coherence(vector: Vector, neighbors: &[Vector]) -> Option<f64> {
+    let magnitude = vector.xy();
+    if magnitude == 0. {
+        return None;
+    }
+    let cosines = neighbors
+        .iter()
+        .filter(|n| n.xy() > 0.)
+        .map(|n| (vector.x * n.x + vector.y * n.y) / (magnitude * n.xy()))
+        .collect::<Vec<_>>();
+    if cosines.is_empty() {
+        None
+    } else {
+        Some(cosines.iter().sum::<f64>() / cosines.len() as f64)
+    }
+}
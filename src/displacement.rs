@@ -0,0 +1,449 @@
+//! Cumulative displacements from chains of consecutive velocities.
+//!
+//! Each velocity covers the interval between its before and after scans. For a sequence of
+//! consecutive pairs those intervals join end to end, so a cell's velocities can be integrated
+//! into the total displacement since the first scan. Uncertainties are propagated assuming the
+//! pairs are independent.
+//!
+//! Intervals that don't join up are gaps. A short gap can be bridged with the mean of the
+//! velocities on either side of it; otherwise the displacement restarts from zero in a new
+//! segment, so a missing pair is never silently treated as no motion.
+
+use Vector;
+use {duration, seconds, string_from_option};
+use chrono::{DateTime, Utc};
+use failure::Error;
+use std::io::Write;
+use std::path::Path;
+use timeseries::{Key, Observation, Series, Store};
+
+/// The current version of the displacement file format.
+pub const VERSION: u32 = 1;
+
+/// Integrates velocity time series into cumulative displacements.
+#[derive(Clone, Copy, Debug)]
+pub struct Integrator {
+    include_flagged: bool,
+    max_gap: Option<f64>,
+    tolerance: f64,
+}
+
+/// Cumulative displacements for many cells.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Displacements {
+    /// The version of the file format.
+    pub version: u32,
+
+    /// The displacement series, one per cell, sorted by cell.
+    pub series: Vec<DisplacementSeries>,
+}
+
+/// The cumulative displacement of one cell.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DisplacementSeries {
+    /// The cell.
+    pub key: Key,
+
+    /// The steps, in time order.
+    pub steps: Vec<Step>,
+}
+
+/// One interval of a cumulative displacement series.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Step {
+    /// The displacement since the start of this step's segment, in meters.
+    pub cumulative: Vector,
+
+    /// The standard deviation of the cumulative displacement, in meters, if every velocity in the
+    /// segment so far had one.
+    pub cumulative_std: Option<Vector>,
+
+    /// The length of the interval, in seconds.
+    pub duration: f64,
+
+    /// The end of the interval.
+    pub end: DateTime<Utc>,
+
+    /// The gap before this step, if there was one.
+    ///
+    /// A bridged gap is its own step. An unbridged gap starts a new segment with this step.
+    pub gap: Option<Gap>,
+
+    /// The displacement during this interval, in meters.
+    pub increment: Vector,
+
+    /// The segment of the series. Segments are separated by unbridged gaps.
+    pub segment: usize,
+
+    /// The velocity file this step came from, or `None` for a bridged gap.
+    pub source: Option<String>,
+
+    /// The start of the interval.
+    pub start: DateTime<Utc>,
+}
+
+/// A gap in a velocity time series.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Gap {
+    /// Whether the gap was bridged with the velocities on either side of it.
+    pub bridged: bool,
+
+    /// The length of the gap, in seconds.
+    pub duration: f64,
+}
+
+/// A velocity with the interval it covers, in meters per second.
+#[derive(Debug)]
+struct Interval<'a> {
+    end: DateTime<Utc>,
+    observation: &'a Observation,
+    start: Option<DateTime<Utc>>,
+    std: Option<Vector>,
+    velocity: Vector,
+}
+
+impl Integrator {
+    /// Creates a new integrator.
+    ///
+    /// By default gaps are never bridged, flagged velocities are skipped, and intervals that miss
+    /// each other by up to a minute are treated as consecutive.
+    pub fn new() -> Integrator {
+        Integrator {
+            include_flagged: false,
+            max_gap: None,
+            tolerance: 60.,
+        }
+    }
+
+    /// Sets the longest gap, in seconds, that is bridged instead of starting a new segment.
+    pub fn max_gap<T: Into<Option<f64>>>(mut self, max_gap: T) -> Integrator {
+        self.max_gap = max_gap.into();
+        self
+    }
+
+    /// Includes velocities flagged as outliers in the integration.
+    ///
+    /// By default they are skipped, and leave a gap.
+    pub fn include_flagged(mut self, include_flagged: bool) -> Integrator {
+        self.include_flagged = include_flagged;
+        self
+    }
+
+    /// Sets how far apart, in seconds, the end of one interval and the start of the next can be
+    /// and still be consecutive.
+    pub fn tolerance(mut self, tolerance: f64) -> Integrator {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Integrates every series in a store.
+    pub fn integrate(&self, store: &Store) -> Displacements {
+        Displacements {
+            version: VERSION,
+            series: store
+                .series
+                .iter()
+                .map(|series| self.integrate_series(series))
+                .filter(|series| !series.steps.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Integrates one cell's series.
+    ///
+    /// Velocities without a recorded separation are skipped, since the interval they cover is
+    /// unknown, and so leave a gap.
+    pub fn integrate_series(&self, series: &Series) -> DisplacementSeries {
+        let intervals = series
+            .observations
+            .iter()
+            .filter(|o| o.velocity.is_included(self.include_flagged))
+            .map(Interval::new)
+            .collect::<Vec<_>>();
+        let mut steps = Vec::new();
+        let mut cumulative = Vector::default();
+        let mut variance = Some(Vector::default());
+        let mut segment = 0;
+        let mut previous: Option<&Interval> = None;
+        for interval in intervals.iter() {
+            let start = match interval.start {
+                Some(start) => start,
+                None => {
+                    warn!(
+                        "Skipping velocity from {} for cell {}, it has no separation",
+                        interval.observation.source,
+                        series.key
+                    );
+                    continue;
+                }
+            };
+            let mut gap = None;
+            if let Some(previous) = previous {
+                let gap_seconds = seconds(start.signed_duration_since(previous.end));
+                if gap_seconds < -self.tolerance {
+                    warn!(
+                        "Skipping velocity from {} for cell {}, it overlaps the previous velocity",
+                        interval.observation.source,
+                        series.key
+                    );
+                    continue;
+                } else if gap_seconds > self.tolerance {
+                    if self.max_gap.map(|max_gap| gap_seconds <= max_gap).unwrap_or(false) {
+                        let velocity = (previous.velocity + interval.velocity) / 2.;
+                        let std = match (previous.std, interval.std) {
+                            (Some(a), Some(b)) => Some(bridge_std(
+                                previous.velocity,
+                                interval.velocity,
+                                a,
+                                b,
+                            )),
+                            _ => None,
+                        };
+                        let increment = velocity * gap_seconds;
+                        cumulative = cumulative + increment;
+                        variance = add_variance(variance, std, gap_seconds);
+                        steps.push(Step {
+                            cumulative: cumulative,
+                            cumulative_std: variance.map(sqrt),
+                            duration: gap_seconds,
+                            end: start,
+                            gap: Some(Gap {
+                                bridged: true,
+                                duration: gap_seconds,
+                            }),
+                            increment: increment,
+                            segment: segment,
+                            source: None,
+                            start: previous.end,
+                        });
+                    } else {
+                        segment += 1;
+                        cumulative = Vector::default();
+                        variance = Some(Vector::default());
+                        gap = Some(Gap {
+                            bridged: false,
+                            duration: gap_seconds,
+                        });
+                    }
+                }
+            }
+            let duration = seconds(interval.end.signed_duration_since(start));
+            let increment = interval.velocity * duration;
+            cumulative = cumulative + increment;
+            variance = add_variance(variance, interval.std, duration);
+            steps.push(Step {
+                cumulative: cumulative,
+                cumulative_std: variance.map(sqrt),
+                duration: duration,
+                end: interval.end,
+                gap: gap,
+                increment: increment,
+                segment: segment,
+                source: Some(interval.observation.source.clone()),
+                start: start,
+            });
+            previous = Some(interval);
+        }
+        DisplacementSeries {
+            key: series.key,
+            steps: steps,
+        }
+    }
+}
+
+impl Default for Integrator {
+    fn default() -> Integrator {
+        Integrator::new()
+    }
+}
+
+impl Displacements {
+    /// Reads displacements from a path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Displacements, Error> {
+        super::read_json(path)
+    }
+
+    /// Writes these displacements to a path.
+    pub fn to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        super::write_json(self, path)
+    }
+
+    /// Writes these displacements as csv, one step per line.
+    pub fn to_csv<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        writeln!(
+            writer,
+            "x,y,grid_size,segment,start,end,duration,dx,dy,dz,cx,cy,cz,cxy,sx,sy,sz,gap,\
+             bridged,source"
+        )?;
+        for series in self.series.iter() {
+            for step in series.steps.iter() {
+                let deviation = |f: fn(&Vector) -> f64| {
+                    string_from_option(step.cumulative_std.as_ref().map(f))
+                };
+                writeln!(
+                    writer,
+                    "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                    series.key.x as f64 / 1000.,
+                    series.key.y as f64 / 1000.,
+                    series.key.size as f64 / 1000.,
+                    step.segment,
+                    step.start.to_rfc3339(),
+                    step.end.to_rfc3339(),
+                    step.duration,
+                    step.increment.x,
+                    step.increment.y,
+                    step.increment.z,
+                    step.cumulative.x,
+                    step.cumulative.y,
+                    step.cumulative.z,
+                    step.cumulative.xy(),
+                    deviation(|s| s.x),
+                    deviation(|s| s.y),
+                    deviation(|s| s.z),
+                    string_from_option(step.gap.map(|g| g.duration)),
+                    string_from_option(step.gap.map(|g| g.bridged)),
+                    step.source.as_ref().map(|s| s.as_str()).unwrap_or("")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Interval<'a> {
+    fn new(observation: &'a Observation) -> Interval<'a> {
+        let velocity = &observation.velocity;
+        let seconds = velocity.unit.seconds();
+        Interval {
+            end: velocity.datetime,
+            observation: observation,
+            start: velocity.separation.map(|separation| {
+                velocity.datetime - duration(separation)
+            }),
+            std: velocity.velocity_std.map(|std| std / seconds),
+            velocity: velocity.velocity / seconds,
+        }
+    }
+}
+
+/// Adds the variance of a velocity's displacement over some seconds, or forgets the variance if
+/// the velocity has no uncertainty.
+fn add_variance(variance: Option<Vector>, std: Option<Vector>, seconds: f64) -> Option<Vector> {
+    match (variance, std) {
+        (Some(variance), Some(std)) => {
+            let std = std * seconds;
+            Some(
+                variance +
+                    Vector {
+                        x: std.x * std.x,
+                        y: std.y * std.y,
+                        z: std.z * std.z,
+                    },
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Returns the uncertainty of the mean of two velocities used to bridge a gap.
+///
+/// This is the uncertainty of the mean itself plus half the difference between the velocities,
+/// since the velocity during the gap could be anywhere between them.
+fn bridge_std(a: Vector, b: Vector, a_std: Vector, b_std: Vector) -> Vector {
+    let component = |a: f64, b: f64, a_std: f64, b_std: f64| {
+        (((a - b) / 2.).powi(2) + (a_std.powi(2) + b_std.powi(2)) / 4.).sqrt()
+    };
+    Vector {
+        x: component(a.x, b.x, a_std.x, b_std.x),
+        y: component(a.y, b.y, a_std.y, b_std.y),
+        z: component(a.z, b.z, a_std.z, b_std.z),
+    }
+}
+
+fn sqrt(vector: Vector) -> Vector {
+    Vector {
+        x: vector.x.sqrt(),
+        y: vector.y.sqrt(),
+        z: vector.z.sqrt(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use timeseries::Key;
+
+    fn observation(hour: u32, separation: Option<f64>, velocity: f64) -> Observation {
+        let datetime = format!("2017-06-01T{:02}:00:00Z", hour);
+        Observation {
+            datetime: datetime.parse().unwrap(),
+            source: format!("{:02}.json", hour),
+            velocity: serde_json::from_value(json!({
+                "after_points": 300,
+                "before_points": 300,
+                "center_of_gravity": {"x": 0., "y": 0., "z": 0.},
+                "datetime": datetime,
+                "grid_size": 10.,
+                "iterations": 10,
+                "separation": separation,
+                "unit": "m/h",
+                "velocity": {"x": velocity, "y": 0., "z": 0.},
+                "velocity_std": {"x": 0.1, "y": 0., "z": 0.},
+                "x": 0.,
+                "y": 0.
+            })).unwrap(),
+        }
+    }
+
+    fn series() -> Series {
+        Series {
+            key: Key::new(0., 0., 10.),
+            observations: vec![
+                observation(1, Some(3600.), 1.),
+                observation(2, Some(3600.), 3.),
+                observation(4, Some(3600.), 5.),
+                observation(6, None, 100.),
+                observation(10, Some(3600.), 1.),
+            ],
+        }
+    }
+
+    fn check(step: &Step, segment: usize, cumulative: f64, variance: f64) {
+        assert_eq!(segment, step.segment);
+        assert!((step.cumulative.x - cumulative).abs() < 1e-9);
+        assert!((step.cumulative_std.unwrap().x - variance.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bridged_gaps() {
+        let steps = Integrator::new()
+            .max_gap(7200.)
+            .integrate_series(&series())
+            .steps;
+        assert_eq!(5, steps.len());
+        check(&steps[0], 0, 1., 0.01);
+        check(&steps[1], 0, 4., 0.02);
+        check(&steps[2], 0, 8., 1.025);
+        assert!(steps[2].gap.unwrap().bridged);
+        assert_eq!(3600., steps[2].gap.unwrap().duration);
+        assert_eq!(None, steps[2].source);
+        check(&steps[3], 0, 13., 1.035);
+        check(&steps[4], 1, 1., 0.01);
+        assert!(!steps[4].gap.unwrap().bridged);
+        assert_eq!(18000., steps[4].gap.unwrap().duration);
+    }
+
+    #[test]
+    fn unbridged_gaps() {
+        let steps = Integrator::new().integrate_series(&series()).steps;
+        assert_eq!(4, steps.len());
+        check(&steps[0], 0, 1., 0.01);
+        check(&steps[1], 0, 4., 0.02);
+        check(&steps[2], 1, 5., 0.01);
+        assert!(!steps[2].gap.unwrap().bridged);
+        assert_eq!(3600., steps[2].gap.unwrap().duration);
+        check(&steps[3], 2, 1., 0.01);
+        assert!(steps.iter().all(|step| step.source != Some("06.json".to_string())));
+    }
+}
@@ -0,0 +1,403 @@
+//! Ice flux through a flux gate.
+//!
+//! A gate is a polyline with a thickness profile along it. Velocities are interpolated at
+//! stations along the gate, and the component normal to the gate is multiplied by the thickness
+//! and integrated with the trapezoidal rule. Flux is positive to the left of the gate, looking
+//! from its first vertex to its last, so reverse the vertices to flip the sign.
+//!
+//! Stations too far from any velocity, or outside the thickness profile, are gaps. Only the
+//! intervals with values at both ends are integrated, and the fraction of the gate they cover is
+//! reported with the flux.
+
+use Vector;
+use failure::Error;
+use string_from_option;
+use geometry::{Polyline, Station};
+use interpolate::Samples;
+use std::io::Write;
+use std::path::Path;
+use velocities::{Unit, Velocity, VelocityFile};
+
+/// A thickness profile needs at least two samples, in order of distance.
+#[derive(Debug, Fail)]
+#[fail(display = "A thickness profile needs at least two samples in increasing distance order")]
+pub struct InvalidThickness {}
+
+/// There were no velocities to calculate the flux from.
+#[derive(Debug, Fail)]
+#[fail(display = "There are no velocities to calculate the flux from")]
+pub struct NoVelocities {}
+
+/// Ice thickness along a gate.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Thickness {
+    /// The distances along the gate, in increasing order.
+    pub distances: Vec<f64>,
+
+    /// The thicknesses at each distance.
+    pub thicknesses: Vec<f64>,
+
+    /// The standard deviation of each thickness.
+    pub uncertainties: Vec<f64>,
+}
+
+/// A flux gate.
+#[derive(Clone, Debug)]
+pub struct Gate {
+    include_flagged: bool,
+    max_distance: Option<f64>,
+    neighbors: usize,
+    polyline: Polyline,
+    ratio: f64,
+    spacing: Option<f64>,
+    thickness: Thickness,
+}
+
+/// The flux through a gate.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Flux {
+    /// The fraction of the gate's length where the flux could be calculated.
+    pub coverage: f64,
+
+    /// The flux, in cubic meters per unit of time.
+    pub flux: f64,
+
+    /// The stations along the gate.
+    pub stations: Vec<FluxStation>,
+
+    /// The unit of the velocities, which sets the unit of time of the flux.
+    pub unit: Unit,
+
+    /// The standard deviation of the flux, in cubic meters per unit of time.
+    pub uncertainty: f64,
+}
+
+/// A station along a flux gate.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct FluxStation {
+    /// The flux per unit width, normal velocity times thickness, if it could be calculated.
+    pub flux: Option<f64>,
+
+    /// The standard deviation of the flux per unit width.
+    pub flux_std: Option<f64>,
+
+    /// The velocity component normal to the gate, scaled by the depth-averaging ratio.
+    pub normal_velocity: Option<f64>,
+
+    /// The station.
+    pub station: Station,
+
+    /// The ice thickness, if the station is within the thickness profile.
+    pub thickness: Option<f64>,
+}
+
+impl Thickness {
+    /// Creates a thickness profile from distances and thicknesses, with no uncertainties.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::flux::Thickness;
+    /// let thickness = Thickness::new(vec![0., 100.], vec![200., 300.]).unwrap();
+    /// assert_eq!(Some((250., 0.)), thickness.at(50.));
+    /// assert_eq!(None, thickness.at(150.));
+    /// ```
+    pub fn new(distances: Vec<f64>, thicknesses: Vec<f64>) -> Result<Thickness, Error> {
+        let uncertainties = vec![0.; distances.len()];
+        Thickness::with_uncertainties(distances, thicknesses, uncertainties)
+    }
+
+    /// Creates a thickness profile with an uncertainty for every thickness.
+    pub fn with_uncertainties(
+        distances: Vec<f64>,
+        thicknesses: Vec<f64>,
+        uncertainties: Vec<f64>,
+    ) -> Result<Thickness, Error> {
+        if distances.len() < 2 || distances.len() != thicknesses.len() ||
+            distances.len() != uncertainties.len() ||
+            distances.windows(2).any(|w| w[1] <= w[0])
+        {
+            return Err(InvalidThickness {}.into());
+        }
+        Ok(Thickness {
+            distances: distances,
+            thicknesses: thicknesses,
+            uncertainties: uncertainties,
+        })
+    }
+
+    /// Reads a thickness profile from a csv file of `distance,thickness[,uncertainty]` lines.
+    ///
+    /// Lines that don't start with two numbers, like a header, are skipped. A missing
+    /// uncertainty is zero.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Thickness, Error> {
+        use std::fs::File;
+        use std::io::{BufRead, BufReader};
+
+        let mut distances = Vec::new();
+        let mut thicknesses = Vec::new();
+        let mut uncertainties = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let numbers = line.split(',')
+                .map(|s| s.trim().parse::<f64>())
+                .take_while(|n| n.is_ok())
+                .map(|n| n.unwrap())
+                .collect::<Vec<_>>();
+            if numbers.len() >= 2 {
+                distances.push(numbers[0]);
+                thicknesses.push(numbers[1]);
+                uncertainties.push(numbers.get(2).cloned().unwrap_or(0.));
+            }
+        }
+        Thickness::with_uncertainties(distances, thicknesses, uncertainties)
+    }
+
+    /// Returns the thickness and its uncertainty at a distance, linearly interpolated, or `None`
+    /// if the distance is outside the profile.
+    pub fn at(&self, distance: f64) -> Option<(f64, f64)> {
+        let n = self.distances.len();
+        if distance < self.distances[0] || distance > self.distances[n - 1] {
+            return None;
+        }
+        let i = self.distances
+            .iter()
+            .position(|&d| d >= distance)
+            .unwrap_or(n - 1)
+            .max(1);
+        let fraction = (distance - self.distances[i - 1]) /
+            (self.distances[i] - self.distances[i - 1]);
+        let interpolate = |values: &[f64]| values[i - 1] + fraction * (values[i] - values[i - 1]);
+        Some((
+            interpolate(&self.thicknesses),
+            interpolate(&self.uncertainties),
+        ))
+    }
+}
+
+impl Gate {
+    /// Creates a new gate from a polyline and a thickness profile along it.
+    ///
+    /// By default the stations are half a velocity cell apart, the surface velocity is used as
+    /// the depth-averaged velocity, and velocities up to one cell size away from a station are
+    /// used.
+    pub fn new(polyline: Polyline, thickness: Thickness) -> Gate {
+        Gate {
+            include_flagged: false,
+            max_distance: None,
+            neighbors: 12,
+            polyline: polyline,
+            ratio: 1.,
+            spacing: None,
+            thickness: thickness,
+        }
+    }
+
+    /// Sets the distance between stations along the gate.
+    pub fn spacing<T: Into<Option<f64>>>(mut self, spacing: T) -> Gate {
+        self.spacing = spacing.into();
+        self
+    }
+
+    /// Sets the ratio of the depth-averaged velocity to the surface velocity.
+    ///
+    /// This is one for plug flow, and about 0.8 for flow dominated by internal deformation.
+    pub fn ratio(mut self, ratio: f64) -> Gate {
+        self.ratio = ratio;
+        self
+    }
+
+    /// Sets the farthest a station can be from a velocity and still be calculated.
+    pub fn max_distance<T: Into<Option<f64>>>(mut self, max_distance: T) -> Gate {
+        self.max_distance = max_distance.into();
+        self
+    }
+
+    /// Sets the number of nearest velocities used to interpolate at each station.
+    pub fn neighbors(mut self, neighbors: usize) -> Gate {
+        self.neighbors = neighbors;
+        self
+    }
+
+    /// Includes velocities flagged as outliers when interpolating along the gate.
+    pub fn include_flagged(mut self, include_flagged: bool) -> Gate {
+        self.include_flagged = include_flagged;
+        self
+    }
+
+    /// Calculates the flux through this gate from the velocities in a velocity file.
+    ///
+    /// The uncertainty of each station combines the thickness uncertainty, the velocity's
+    /// bootstrap uncertainty if it has one, and the spread of the interpolated velocities. Errors
+    /// along the gate are assumed to be fully correlated, which gives a conservative total.
+    pub fn flux(&self, velocity_file: &VelocityFile) -> Result<Flux, Error> {
+        let velocities = velocity_file.included(self.include_flagged);
+        if velocities.is_empty() {
+            return Err(NoVelocities {}.into());
+        }
+        let unit = velocities[0].unit;
+        let scale = |v: &&Velocity| unit.seconds() / v.unit.seconds();
+        let positions = velocities
+            .iter()
+            .map(|v| [v.center_of_gravity.x, v.center_of_gravity.y, 0.])
+            .collect::<Vec<_>>();
+        let samples = Samples::new(
+            positions.clone(),
+            velocities.iter().map(|v| v.velocity * scale(v)).collect(),
+        );
+        let stds = Samples::new(
+            positions,
+            velocities
+                .iter()
+                .map(|v| v.velocity_std.unwrap_or_default() * scale(v))
+                .collect(),
+        );
+        let cell_size = velocity_file.geometry.cell_size;
+        let max_distance = self.max_distance.unwrap_or(cell_size);
+        let stations = self.polyline
+            .stations(self.spacing.unwrap_or(cell_size / 2.))?
+            .into_iter()
+            .map(|station| {
+                let thickness = self.thickness.at(station.distance);
+                let normal = if samples.distance(station.x, station.y) > max_distance {
+                    None
+                } else {
+                    let (velocity, spread) =
+                        samples.idw(station.x, station.y, 2., self.neighbors);
+                    let (std, _) = stds.idw(station.x, station.y, 2., self.neighbors);
+                    let normal = normal_component(velocity, station.direction);
+                    let std = normal_std(std, station.direction);
+                    Some((
+                        normal * self.ratio,
+                        (std.powi(2) + spread.powi(2)).sqrt() * self.ratio,
+                    ))
+                };
+                let flux = match (normal, thickness) {
+                    (Some((u, u_std)), Some((h, h_std))) => {
+                        Some((u * h, ((h * u_std).powi(2) + (u * h_std).powi(2)).sqrt()))
+                    }
+                    _ => None,
+                };
+                FluxStation {
+                    flux: flux.map(|f| f.0),
+                    flux_std: flux.map(|f| f.1),
+                    normal_velocity: normal.map(|n| n.0),
+                    station: station,
+                    thickness: thickness.map(|t| t.0),
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut flux = 0.;
+        let mut uncertainty = 0.;
+        let mut covered = 0.;
+        for pair in stations.windows(2) {
+            if let (Some(a), Some(b), Some(a_std), Some(b_std)) =
+                (pair[0].flux, pair[1].flux, pair[0].flux_std, pair[1].flux_std)
+            {
+                let width = pair[1].station.distance - pair[0].station.distance;
+                flux += width * (a + b) / 2.;
+                uncertainty += width * (a_std + b_std) / 2.;
+                covered += width;
+            }
+        }
+        let length = self.polyline.length();
+        Ok(Flux {
+            coverage: if length > 0. { covered / length } else { 0. },
+            flux: flux,
+            stations: stations,
+            unit: unit,
+            uncertainty: uncertainty,
+        })
+    }
+}
+
+impl Flux {
+    /// Writes this flux's stations as csv, one station per line.
+    pub fn stations_to_csv<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        writeln!(
+            writer,
+            "distance,x,y,normal_velocity,thickness,flux,flux_std,unit"
+        )?;
+        for station in self.stations.iter() {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{}",
+                station.station.distance,
+                station.station.x,
+                station.station.y,
+                string_from_option(station.normal_velocity),
+                string_from_option(station.thickness),
+                string_from_option(station.flux),
+                string_from_option(station.flux_std),
+                self.unit
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the component of a vector along the left-hand normal of a direction.
+fn normal_component(vector: Vector, direction: (f64, f64)) -> f64 {
+    -vector.x * direction.1 + vector.y * direction.0
+}
+
+/// Returns the standard deviation of the normal component of a vector with independent
+/// component uncertainties.
+fn normal_std(std: Vector, direction: (f64, f64)) -> f64 {
+    ((std.x * direction.1).powi(2) + (std.y * direction.0).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geometry::Geometry;
+    use serde_json;
+
+    #[test]
+    fn diagonal_gate() {
+        let mut velocities = Vec::new();
+        for row in 0..10 {
+            for col in 0..10 {
+                let (x, y) = (col as f64 * 10., row as f64 * 10.);
+                velocities.push(
+                    serde_json::from_value::<Velocity>(json!({
+                        "after_points": 300,
+                        "before_points": 300,
+                        "center_of_gravity": {"x": x + 5., "y": y + 5., "z": 0.},
+                        "col": col,
+                        "datetime": "2017-06-01T00:00:00Z",
+                        "grid_size": 10.,
+                        "iterations": 10,
+                        "row": row,
+                        "unit": "m/day",
+                        "velocity": {"x": -1., "y": 1., "z": 0.},
+                        "velocity_std": {"x": 0.3, "y": 0.4, "z": 0.},
+                        "x": x,
+                        "y": y
+                    })).unwrap(),
+                );
+            }
+        }
+        let velocity_file = VelocityFile::new(Geometry::new(10.).unwrap(), velocities);
+        let polyline = Polyline::new(vec![(0., 0.), (100., 100.)]).unwrap();
+        let thickness = Thickness::with_uncertainties(
+            vec![0., 200.],
+            vec![100., 100.],
+            vec![10., 10.],
+        ).unwrap();
+        let flux = Gate::new(polyline, thickness).flux(&velocity_file).unwrap();
+
+        // The normal velocity is sqrt(2) m/day with a standard deviation of sqrt(0.125) m/day,
+        // over a gate 100 * sqrt(2) m long and 100 +/- 10 m thick.
+        let length = 100. * 2f64.sqrt();
+        let flux_std = ((100f64 * 0.125f64.sqrt()).powi(2) + (2f64.sqrt() * 10.).powi(2)).sqrt();
+        assert_eq!(Unit::MetersPerDay, flux.unit);
+        assert!((flux.coverage - 1.).abs() < 1e-9);
+        assert!((flux.flux - 20_000.).abs() < 1e-6);
+        assert!((flux.uncertainty - flux_std * length).abs() < 1e-6);
+        for station in flux.stations.iter() {
+            assert!((station.normal_velocity.unwrap() - 2f64.sqrt()).abs() < 1e-9);
+            assert!((station.flux_std.unwrap() - flux_std).abs() < 1e-6);
+        }
+    }
+}
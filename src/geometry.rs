@@ -0,0 +1,459 @@
+//! Regular grid geometry.
+//!
+//! Grid cells are addressed by row (northing) and column (easting). Indices are computed with
+//! `floor`, so cells on either side of the origin are the same size. Cells are usually disjoint,
+//! but a stride smaller than the cell size places overlapping cells every `stride` units.
+
+use failure::Error;
+use std::path::Path;
+
+/// The cell size must be positive.
+#[derive(Debug, Fail)]
+#[fail(display = "The cell size must be positive, not {}", _0)]
+pub struct InvalidCellSize(f64);
+
+/// The distance between stations must be positive.
+#[derive(Debug, Fail)]
+#[fail(display = "The station spacing must be positive, not {}", _0)]
+pub struct InvalidSpacing(f64);
+
+/// The distance between adjacent cells must be positive.
+#[derive(Debug, Fail)]
+#[fail(display = "The stride must be positive, not {}", _0)]
+pub struct InvalidStride(f64);
+
+/// A polyline needs at least two vertices.
+#[derive(Debug, Fail)]
+#[fail(display = "A polyline needs at least two vertices, got {}", _0)]
+pub struct TooFewVertices(usize);
+
+/// The geometry of a regular grid of square cells.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Geometry {
+    /// The size of each cell, in the units of the point cloud.
+    pub cell_size: f64,
+
+    /// The easting of the lower-left corner of cell (0, 0).
+    pub origin_x: f64,
+
+    /// The northing of the lower-left corner of cell (0, 0).
+    pub origin_y: f64,
+
+    /// The distance between the lower-left corners of adjacent cells, if not the cell size.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stride: Option<f64>,
+}
+
+/// The extents of a grid cell.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bounds {
+    /// The minimum easting.
+    pub min_x: f64,
+
+    /// The minimum northing.
+    pub min_y: f64,
+
+    /// The maximum easting.
+    pub max_x: f64,
+
+    /// The maximum northing.
+    pub max_y: f64,
+}
+
+/// The row and column of a grid cell.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct Index {
+    /// The row, which increases with northing.
+    pub row: i64,
+
+    /// The column, which increases with easting.
+    pub col: i64,
+}
+
+/// A point along a polyline.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Station {
+    /// The unit direction of the polyline at this point, as (east, north).
+    pub direction: (f64, f64),
+
+    /// The distance along the polyline from its first vertex.
+    pub distance: f64,
+
+    /// The easting.
+    pub x: f64,
+
+    /// The northing.
+    pub y: f64,
+}
+
+/// A line of straight segments between vertices.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Polyline {
+    /// The vertices, as (easting, northing).
+    pub vertices: Vec<(f64, f64)>,
+}
+
+impl Geometry {
+    /// Creates a new geometry with the given cell size and an origin at (0, 0).
+    ///
+    /// Returns an error if the cell size isn't positive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Geometry;
+    /// let geometry = Geometry::new(12.5).unwrap();
+    /// assert_eq!(12.5, geometry.cell_size);
+    /// assert!(Geometry::new(0.).is_err());
+    /// ```
+    pub fn new(cell_size: f64) -> Result<Geometry, Error> {
+        if !(cell_size > 0.) {
+            return Err(InvalidCellSize(cell_size).into());
+        }
+        Ok(Geometry {
+            cell_size: cell_size,
+            origin_x: 0.,
+            origin_y: 0.,
+            stride: None,
+        })
+    }
+
+    /// Sets the origin of this geometry.
+    pub fn origin(mut self, x: f64, y: f64) -> Geometry {
+        self.origin_x = x;
+        self.origin_y = y;
+        self
+    }
+
+    /// Sets the distance between the lower-left corners of adjacent cells.
+    ///
+    /// Strides smaller than the cell size produce overlapping cells. Returns an error if the
+    /// stride isn't positive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Geometry;
+    /// let geometry = Geometry::new(100.).unwrap();
+    /// assert!(geometry.stride(50.).unwrap().is_overlapping());
+    /// assert!(geometry.stride(-50.).is_err());
+    /// ```
+    pub fn stride(mut self, stride: f64) -> Result<Geometry, Error> {
+        if !(stride > 0.) {
+            return Err(InvalidStride(stride).into());
+        }
+        self.stride = if stride == self.cell_size {
+            None
+        } else {
+            Some(stride)
+        };
+        Ok(self)
+    }
+
+    /// Returns the distance between the lower-left corners of adjacent cells.
+    pub fn spacing(&self) -> f64 {
+        self.stride.unwrap_or(self.cell_size)
+    }
+
+    /// Returns true if adjacent cells overlap.
+    pub fn is_overlapping(&self) -> bool {
+        self.spacing() < self.cell_size
+    }
+
+    /// Returns the index of the cell whose lower-left corner is closest below and to the left of
+    /// this easting and northing.
+    ///
+    /// For disjoint cells, this is the cell that contains the point.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::{Geometry, Index};
+    /// let geometry = Geometry::new(100.).unwrap();
+    /// assert_eq!(Index { row: 0, col: 1 }, geometry.index(150., 50.));
+    /// assert_eq!(Index { row: -1, col: -1 }, geometry.index(-50., -50.));
+    /// let geometry = Geometry::new(7.5).unwrap().origin(0.25, 0.);
+    /// assert_eq!(Index { row: 0, col: 1 }, geometry.index(7.8, 7.4));
+    /// ```
+    pub fn index(&self, x: f64, y: f64) -> Index {
+        let spacing = self.spacing();
+        Index {
+            row: ((y - self.origin_y) / spacing).floor() as i64,
+            col: ((x - self.origin_x) / spacing).floor() as i64,
+        }
+    }
+
+    /// Returns the indices of all cells that contain this easting and northing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::{Geometry, Index};
+    /// let geometry = Geometry::new(100.).unwrap();
+    /// assert_eq!(vec![Index { row: 0, col: 1 }], geometry.containing(150., 50.));
+    /// let geometry = Geometry::new(100.).unwrap().stride(50.).unwrap();
+    /// assert_eq!(
+    ///     vec![
+    ///         Index { row: -1, col: 2 },
+    ///         Index { row: -1, col: 3 },
+    ///         Index { row: 0, col: 2 },
+    ///         Index { row: 0, col: 3 },
+    ///     ],
+    ///     geometry.containing(160., 10.)
+    /// );
+    /// ```
+    pub fn containing(&self, x: f64, y: f64) -> Vec<Index> {
+        let (min_row, max_row) = self.range(y - self.origin_y);
+        let (min_col, max_col) = self.range(x - self.origin_x);
+        let mut indices = Vec::new();
+        for row in min_row..(max_row + 1) {
+            for col in min_col..(max_col + 1) {
+                indices.push(Index { row: row, col: col });
+            }
+        }
+        indices
+    }
+
+    /// Returns the easting of the left edge of this column.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Geometry;
+    /// let geometry = Geometry::new(100.).unwrap().origin(10., 20.);
+    /// assert_eq!(-90., geometry.x(-1));
+    /// ```
+    pub fn x(&self, col: i64) -> f64 {
+        self.origin_x + col as f64 * self.spacing()
+    }
+
+    /// Returns the northing of the bottom edge of this row.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Geometry;
+    /// let geometry = Geometry::new(100.).unwrap().origin(10., 20.);
+    /// assert_eq!(220., geometry.y(2));
+    /// ```
+    pub fn y(&self, row: i64) -> f64 {
+        self.origin_y + row as f64 * self.spacing()
+    }
+
+    /// Returns the bounds of a square that starts at this index and is `size` wide.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::{Geometry, Index};
+    /// let geometry = Geometry::new(12.5).unwrap();
+    /// let bounds = geometry.bounds(Index { row: -1, col: 2 }, 25.);
+    /// assert_eq!(25., bounds.min_x);
+    /// assert_eq!(50., bounds.max_x);
+    /// assert_eq!(-12.5, bounds.min_y);
+    /// assert_eq!(12.5, bounds.max_y);
+    /// ```
+    pub fn bounds(&self, index: Index, size: f64) -> Bounds {
+        Bounds::new(self.x(index.col), self.y(index.row), size)
+    }
+
+    fn range(&self, offset: f64) -> (i64, i64) {
+        let spacing = self.spacing();
+        let max = (offset / spacing).floor() as i64;
+        if self.stride.is_some() {
+            (((offset - self.cell_size) / spacing).floor() as i64 + 1, max)
+        } else {
+            (max, max)
+        }
+    }
+}
+
+impl Bounds {
+    /// Creates new bounds for a square with a lower-left corner and a size.
+    pub fn new(x: f64, y: f64, size: f64) -> Bounds {
+        Bounds {
+            min_x: x,
+            min_y: y,
+            max_x: x + size,
+            max_y: y + size,
+        }
+    }
+
+    /// Returns the center of these bounds.
+    pub fn center(&self) -> (f64, f64) {
+        (
+            (self.min_x + self.max_x) / 2.,
+            (self.min_y + self.max_y) / 2.,
+        )
+    }
+
+    /// Returns true if a point is inside these bounds, including the minimum edges but not the
+    /// maximum edges.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Bounds;
+    /// let bounds = Bounds::new(0., 0., 10.);
+    /// assert!(bounds.contains(0., 5.));
+    /// assert!(!bounds.contains(10., 5.));
+    /// ```
+    pub fn contains(&self, x: f64, y: f64) -> bool {
+        x >= self.min_x && x < self.max_x && y >= self.min_y && y < self.max_y
+    }
+
+    /// Returns true if these bounds share an edge, a corner, or some area with other bounds.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Bounds;
+    /// let bounds = Bounds::new(0., 0., 10.);
+    /// assert!(bounds.touches(&Bounds::new(10., 10., 5.)));
+    /// assert!(!bounds.touches(&Bounds::new(10.5, 0., 5.)));
+    /// ```
+    pub fn touches(&self, other: &Bounds) -> bool {
+        self.min_x <= other.max_x && other.min_x <= self.max_x && self.min_y <= other.max_y &&
+            other.min_y <= self.max_y
+    }
+}
+
+impl Polyline {
+    /// Creates a new polyline from its vertices.
+    pub fn new(vertices: Vec<(f64, f64)>) -> Result<Polyline, Error> {
+        if vertices.len() < 2 {
+            Err(TooFewVertices(vertices.len()).into())
+        } else {
+            Ok(Polyline { vertices: vertices })
+        }
+    }
+
+    /// Reads a polyline from a csv file with one `x,y` vertex per line.
+    ///
+    /// Lines that don't start with two numbers, like a header, are skipped.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Polyline, Error> {
+        points_from_path(path).and_then(Polyline::new)
+    }
+
+    /// Returns the total length of this polyline.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Polyline;
+    /// let polyline = Polyline::new(vec![(0., 0.), (3., 4.), (3., 10.)]).unwrap();
+    /// assert_eq!(11., polyline.length());
+    /// ```
+    pub fn length(&self) -> f64 {
+        self.segments()
+            .map(|(a, b)| ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt())
+            .sum()
+    }
+
+    /// Returns the segments of this polyline, from the first vertex to the last.
+    pub fn segments<'a>(&'a self) -> Box<Iterator<Item = ((f64, f64), (f64, f64))> + 'a> {
+        Box::new(self.vertices.iter().zip(self.vertices.iter().skip(1)).map(
+            |(&a, &b)| (a, b),
+        ))
+    }
+
+    /// Returns stations every `spacing` along this polyline, starting at the first vertex and
+    /// always ending at the last.
+    ///
+    /// A station on a vertex takes the direction of the segment that starts there. Returns an
+    /// error if the spacing isn't positive.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Polyline;
+    /// let polyline = Polyline::new(vec![(0., 0.), (6., 0.), (6., 4.)]).unwrap();
+    /// let stations = polyline.stations(4.).unwrap();
+    /// let distances = stations.iter().map(|s| s.distance).collect::<Vec<_>>();
+    /// assert_eq!(vec![0., 4., 8., 10.], distances);
+    /// assert_eq!((6., 2.), (stations[2].x, stations[2].y));
+    /// assert_eq!((0., 1.), stations[2].direction);
+    /// assert!(polyline.stations(0.).is_err());
+    /// ```
+    pub fn stations(&self, spacing: f64) -> Result<Vec<Station>, Error> {
+        if !(spacing > 0.) {
+            return Err(InvalidSpacing(spacing).into());
+        }
+        let length = self.length();
+        let count = (length / spacing).ceil() as usize;
+        let mut distances = (0..count).map(|i| i as f64 * spacing).collect::<Vec<_>>();
+        distances.push(length);
+        let mut stations = Vec::with_capacity(distances.len());
+        let mut segments = self.segments()
+            .filter(|&(a, b)| a != b)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .peekable();
+        let mut start = 0.;
+        for distance in distances {
+            while let Some(&(a, b)) = segments.peek() {
+                let segment_length = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+                let is_last = segments.len() == 1;
+                if distance < start + segment_length || is_last {
+                    let fraction = ((distance - start) / segment_length).min(1.);
+                    stations.push(Station {
+                        direction: ((b.0 - a.0) / segment_length, (b.1 - a.1) / segment_length),
+                        distance: distance,
+                        x: a.0 + fraction * (b.0 - a.0),
+                        y: a.1 + fraction * (b.1 - a.1),
+                    });
+                    break;
+                }
+                start += segment_length;
+                segments.next();
+            }
+        }
+        Ok(stations)
+    }
+
+    /// Returns where the segment from `a` to `b` first crosses this polyline, as a fraction of the
+    /// way from `a` to `b`, or `None` if it doesn't cross.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Polyline;
+    /// let polyline = Polyline::new(vec![(0., -1.), (0., 1.)]).unwrap();
+    /// assert_eq!(Some(0.25), polyline.crossing((-1., 0.), (3., 0.)));
+    /// assert_eq!(None, polyline.crossing((1., 0.), (3., 0.)));
+    /// ```
+    pub fn crossing(&self, a: (f64, f64), b: (f64, f64)) -> Option<f64> {
+        let mut first: Option<f64> = None;
+        for (c, d) in self.segments() {
+            let denominator = (b.0 - a.0) * (d.1 - c.1) - (b.1 - a.1) * (d.0 - c.0);
+            if denominator == 0. {
+                continue;
+            }
+            let t = ((c.0 - a.0) * (d.1 - c.1) - (c.1 - a.1) * (d.0 - c.0)) / denominator;
+            let u = ((c.0 - a.0) * (b.1 - a.1) - (c.1 - a.1) * (b.0 - a.0)) / denominator;
+            if t >= 0. && t <= 1. && u >= 0. && u <= 1. {
+                first = Some(first.map(|f| f.min(t)).unwrap_or(t));
+            }
+        }
+        first
+    }
+}
+
+/// Reads points from a csv file with one `x,y` point per line.
+///
+/// Lines that don't start with two numbers, like a header, are skipped. Any further columns are
+/// ignored.
+pub fn points_from_path<P: AsRef<Path>>(path: P) -> Result<Vec<(f64, f64)>, Error> {
+    use std::fs::File;
+    use std::io::{BufRead, BufReader};
+
+    let mut points = Vec::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let mut numbers = line.split(',').map(|s| s.trim().parse::<f64>());
+        if let (Some(Ok(x)), Some(Ok(y))) = (numbers.next(), numbers.next()) {
+            points.push((x, y));
+        }
+    }
+    Ok(points)
+}
@@ -0,0 +1,718 @@
+//! Interpolation of velocity fields onto regular grids.
+//!
+//! Velocities are measured on an irregular set of cells: some cells were culled or failed, some
+//! were grown and some were refined. This module interpolates those velocities onto a regular
+//! `Field`, filling the gaps. Every node of the field records the distance to the nearest
+//! measured velocity and, where the method provides one, an uncertainty.
+
+use Vector;
+use string_from_option;
+use failure::Error;
+use kdtree::KdTree;
+use std::f64;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use velocities::{Unit, VelocityFile};
+
+/// The requested interpolation method does not exist.
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid interpolation method: {}", _0)]
+pub struct InvalidMethod(String);
+
+/// There are too few velocities to interpolate with the requested method.
+#[derive(Debug, Fail)]
+#[fail(display = "Too few velocities to interpolate: {}", _0)]
+pub struct TooFewVelocities(usize);
+
+/// An interpolation method.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Method {
+    /// Inverse distance weighting of the nearest velocities.
+    Idw {
+        /// The power of the inverse distance.
+        power: f64,
+    },
+
+    /// Discrete Sibson (natural neighbor) interpolation, calculated on the output grid.
+    NaturalNeighbor,
+
+    /// Ordinary kriging with an exponential variogram fitted to the velocities.
+    Kriging,
+}
+
+/// An exponential variogram model, `nugget + sill * (1 - exp(-3h / range))`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Variogram {
+    /// The semivariance at zero distance.
+    pub nugget: f64,
+
+    /// The distance at which the semivariance reaches 95% of the sill.
+    pub range: f64,
+
+    /// The partial sill, the semivariance above the nugget at infinite distance.
+    pub sill: f64,
+}
+
+/// Interpolates velocities onto a regular grid.
+#[derive(Debug)]
+pub struct Interpolator {
+    include_flagged: bool,
+    max_distance: Option<f64>,
+    method: Method,
+    neighbors: usize,
+    spacing: Option<f64>,
+}
+
+/// A regular grid of interpolated velocities.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Field {
+    /// The number of columns.
+    pub cols: usize,
+
+    /// The method used to interpolate the velocities.
+    pub method: Method,
+
+    /// The nodes, row by row from south to north, each row from west to east.
+    pub nodes: Vec<Node>,
+
+    /// The easting of the south-west node.
+    pub origin_x: f64,
+
+    /// The northing of the south-west node.
+    pub origin_y: f64,
+
+    /// The number of rows.
+    pub rows: usize,
+
+    /// The distance between adjacent nodes.
+    pub spacing: f64,
+
+    /// The unit of the velocities.
+    pub unit: Unit,
+
+    /// The variogram fitted for kriging.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variogram: Option<Variogram>,
+}
+
+/// A node in a field.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Node {
+    /// The distance to the nearest measured velocity.
+    pub distance: f64,
+
+    /// The uncertainty of the interpolated velocity, in velocity units.
+    ///
+    /// For inverse distance weighting this is the weighted spread of the neighbors' horizontal
+    /// velocities around the interpolated velocity, for natural neighbor interpolation it is the
+    /// spread of the contributing horizontal velocities, and for kriging it is the kriging
+    /// standard deviation of each horizontal component.
+    pub uncertainty: Option<f64>,
+
+    /// The interpolated velocity, or `None` if the node is too far from any measurement.
+    pub velocity: Option<Vector>,
+
+    /// The easting of the node.
+    pub x: f64,
+
+    /// The northing of the node.
+    pub y: f64,
+}
+
+/// Velocities at scattered positions, with a kd-tree for neighbor queries.
+#[derive(Debug)]
+pub(crate) struct Samples {
+    positions: Vec<[f64; 3]>,
+    tree: KdTree,
+    values: Vec<Vector>,
+}
+
+impl Interpolator {
+    /// Creates a new interpolator that uses inverse distance weighting with a power of two.
+    pub fn new() -> Interpolator {
+        Interpolator {
+            include_flagged: false,
+            max_distance: None,
+            method: Method::Idw { power: 2. },
+            neighbors: 12,
+            spacing: None,
+        }
+    }
+
+    /// Sets the interpolation method.
+    pub fn method(mut self, method: Method) -> Interpolator {
+        self.method = method;
+        self
+    }
+
+    /// Sets the number of nearest velocities used for inverse distance weighting and kriging.
+    pub fn neighbors(mut self, neighbors: usize) -> Interpolator {
+        self.neighbors = neighbors;
+        self
+    }
+
+    /// Sets the distance beyond which nodes are left empty.
+    pub fn max_distance<T: Into<Option<f64>>>(mut self, max_distance: T) -> Interpolator {
+        self.max_distance = max_distance.into();
+        self
+    }
+
+    /// Sets the node spacing of the output grid, which defaults to the velocity file's cell size.
+    pub fn spacing<T: Into<Option<f64>>>(mut self, spacing: T) -> Interpolator {
+        self.spacing = spacing.into();
+        self
+    }
+
+    /// Includes velocities flagged as outliers in the interpolation. By default they are
+    /// skipped.
+    pub fn include_flagged(mut self, include_flagged: bool) -> Interpolator {
+        self.include_flagged = include_flagged;
+        self
+    }
+
+    /// Interpolates the velocities in a velocity file onto a regular grid.
+    ///
+    /// The grid covers every velocity's cell, with nodes at the centers of squares of the node
+    /// spacing. Velocities are placed at their centers of gravity and converted to the unit of
+    /// the first velocity.
+    pub fn interpolate(&self, velocity_file: &VelocityFile) -> Result<Field, Error> {
+        let velocities = velocity_file.included(self.include_flagged);
+        let minimum = match self.method {
+            Method::Kriging => 3,
+            _ => 1,
+        };
+        if velocities.len() < minimum {
+            return Err(TooFewVelocities(velocities.len()).into());
+        }
+        let unit = velocities[0].unit;
+        let spacing = self.spacing.unwrap_or(velocity_file.geometry.cell_size);
+        let min_x = velocities.iter().map(|v| v.bounds.min_x).fold(
+            f64::INFINITY,
+            f64::min,
+        );
+        let min_y = velocities.iter().map(|v| v.bounds.min_y).fold(
+            f64::INFINITY,
+            f64::min,
+        );
+        let max_x = velocities.iter().map(|v| v.bounds.max_x).fold(
+            f64::NEG_INFINITY,
+            f64::max,
+        );
+        let max_y = velocities.iter().map(|v| v.bounds.max_y).fold(
+            f64::NEG_INFINITY,
+            f64::max,
+        );
+        let positions = velocities
+            .iter()
+            .map(|v| [v.center_of_gravity.x, v.center_of_gravity.y, 0.])
+            .collect::<Vec<_>>();
+        let values = velocities
+            .iter()
+            .map(|v| v.velocity * (unit.seconds() / v.unit.seconds()))
+            .collect();
+        let samples = Samples::new(positions, values);
+        let cols = (((max_x - min_x) / spacing).ceil() as usize).max(1);
+        let rows = (((max_y - min_y) / spacing).ceil() as usize).max(1);
+        let mut field = Field {
+            cols: cols,
+            method: self.method,
+            nodes: Vec::with_capacity(rows * cols),
+            origin_x: min_x + spacing / 2.,
+            origin_y: min_y + spacing / 2.,
+            rows: rows,
+            spacing: spacing,
+            unit: unit,
+            variogram: None,
+        };
+        for row in 0..rows {
+            for col in 0..cols {
+                let x = field.origin_x + col as f64 * spacing;
+                let y = field.origin_y + row as f64 * spacing;
+                let distance = samples.distance(x, y);
+                field.nodes.push(Node {
+                    distance: distance,
+                    uncertainty: None,
+                    velocity: None,
+                    x: x,
+                    y: y,
+                });
+            }
+        }
+        match self.method {
+            Method::Idw { power } => {
+                for node in field.nodes.iter_mut() {
+                    if self.is_within(node.distance) {
+                        let (velocity, uncertainty) =
+                            samples.idw(node.x, node.y, power, self.neighbors);
+                        node.velocity = Some(velocity);
+                        node.uncertainty = Some(uncertainty);
+                    }
+                }
+            }
+            Method::NaturalNeighbor => self.natural_neighbor(&samples, &mut field),
+            Method::Kriging => {
+                let variogram = Variogram::fit(&samples)?;
+                for node in field.nodes.iter_mut() {
+                    if self.is_within(node.distance) {
+                        let (velocity, uncertainty) = match variogram.krige(
+                            &samples,
+                            node.x,
+                            node.y,
+                            self.neighbors,
+                        ) {
+                            Some(estimate) => estimate,
+                            None => samples.idw(node.x, node.y, 2., self.neighbors),
+                        };
+                        node.velocity = Some(velocity);
+                        node.uncertainty = Some(uncertainty);
+                    }
+                }
+                field.variogram = Some(variogram);
+            }
+        }
+        Ok(field)
+    }
+
+    fn is_within(&self, distance: f64) -> bool {
+        self.max_distance.map(|m| distance <= m).unwrap_or(true)
+    }
+
+    /// Discrete Sibson interpolation.
+    ///
+    /// Every node takes the value of its nearest velocity and gives that value to every node
+    /// within the same distance of it. A node's velocity is the mean of the values it was given.
+    fn natural_neighbor(&self, samples: &Samples, field: &mut Field) {
+        let n = field.nodes.len();
+        let mut sums = vec![Vector::default(); n];
+        let mut squares = vec![0.; n];
+        let mut counts = vec![0usize; n];
+        for row in 0..field.rows {
+            for col in 0..field.cols {
+                let node = field.nodes[row * field.cols + col];
+                if !self.is_within(node.distance) {
+                    continue;
+                }
+                let value = match samples.tree.nearest(&[node.x, node.y, 0.]) {
+                    Some((i, _)) => samples.values[i],
+                    None => continue,
+                };
+                let reach = (node.distance / field.spacing).floor() as i64;
+                let radius2 = (node.distance / field.spacing).powi(2) + 1e-9;
+                for dr in -reach..(reach + 1) {
+                    let r = row as i64 + dr;
+                    if r < 0 || r >= field.rows as i64 {
+                        continue;
+                    }
+                    for dc in -reach..(reach + 1) {
+                        let c = col as i64 + dc;
+                        if c < 0 || c >= field.cols as i64 ||
+                            (dr * dr + dc * dc) as f64 > radius2
+                        {
+                            continue;
+                        }
+                        let j = r as usize * field.cols + c as usize;
+                        sums[j] = sums[j] + value;
+                        squares[j] += value.x * value.x + value.y * value.y;
+                        counts[j] += 1;
+                    }
+                }
+            }
+        }
+        for (j, node) in field.nodes.iter_mut().enumerate() {
+            if counts[j] > 0 && self.is_within(node.distance) {
+                let count = counts[j] as f64;
+                let mean = sums[j] / count;
+                let variance = squares[j] / count - (mean.x * mean.x + mean.y * mean.y);
+                node.velocity = Some(mean);
+                node.uncertainty = Some(variance.max(0.).sqrt());
+            }
+        }
+    }
+}
+
+impl Default for Interpolator {
+    fn default() -> Interpolator {
+        Interpolator::new()
+    }
+}
+
+impl Samples {
+    /// Creates new samples from positions and their velocities.
+    pub(crate) fn new(positions: Vec<[f64; 3]>, values: Vec<Vector>) -> Samples {
+        Samples {
+            tree: KdTree::new(positions.clone(), 2),
+            positions: positions,
+            values: values,
+        }
+    }
+
+    /// Returns the distance to the nearest sample, or infinity if there are none.
+    pub(crate) fn distance(&self, x: f64, y: f64) -> f64 {
+        self.tree
+            .nearest(&[x, y, 0.])
+            .map(|(_, d)| d.sqrt())
+            .unwrap_or(f64::INFINITY)
+    }
+
+    /// Inverse distance weighting, returning the velocity and the weighted spread.
+    pub(crate) fn idw(&self, x: f64, y: f64, power: f64, neighbors: usize) -> (Vector, f64) {
+        let nearest = self.tree.nearest_k(&[x, y, 0.], neighbors.max(1));
+        if let Some(&(i, distance2)) = nearest.first() {
+            if distance2 == 0. {
+                return (self.values[i], 0.);
+            }
+        }
+        let mut weight = 0.;
+        let mut sum = Vector::default();
+        for &(i, distance2) in nearest.iter() {
+            let w = distance2.sqrt().powf(-power);
+            sum = sum + self.values[i] * w;
+            weight += w;
+        }
+        let velocity = sum / weight;
+        let spread = nearest
+            .iter()
+            .map(|&(i, distance2)| {
+                distance2.sqrt().powf(-power) * (self.values[i] - velocity).xy().powi(2)
+            })
+            .sum::<f64>() / weight;
+        (velocity, spread.sqrt())
+    }
+}
+
+impl Variogram {
+    /// Fits an exponential variogram to the horizontal velocity components of some samples.
+    ///
+    /// The empirical semivariance is pooled over the x and y components, binned by distance out
+    /// to half of the samples' extent, and the model is fitted by weighted least squares.
+    fn fit(samples: &Samples) -> Result<Variogram, Error> {
+        const BINS: usize = 15;
+        const MAX_SAMPLES: usize = 1000;
+
+        let n = samples.positions.len();
+        let step = (n + MAX_SAMPLES - 1) / MAX_SAMPLES;
+        let indices = (0..n).filter(|i| i % step == 0).collect::<Vec<_>>();
+        let extent = {
+            let xs = indices.iter().map(|&i| samples.positions[i][0]);
+            let ys = indices.iter().map(|&i| samples.positions[i][1]);
+            let (min_x, max_x) = xs.fold((f64::INFINITY, f64::NEG_INFINITY), |(a, b), x| {
+                (a.min(x), b.max(x))
+            });
+            let (min_y, max_y) = ys.fold((f64::INFINITY, f64::NEG_INFINITY), |(a, b), y| {
+                (a.min(y), b.max(y))
+            });
+            ((max_x - min_x).powi(2) + (max_y - min_y).powi(2)).sqrt()
+        };
+        let max_lag = extent / 2.;
+        let width = max_lag / BINS as f64;
+        let mut distances = vec![0.; BINS];
+        let mut semivariances = vec![0.; BINS];
+        let mut counts = vec![0usize; BINS];
+        for (a, &i) in indices.iter().enumerate() {
+            for &j in indices[(a + 1)..].iter() {
+                let h = distance(&samples.positions[i], &samples.positions[j]);
+                if h >= max_lag || width <= 0. {
+                    continue;
+                }
+                let bin = ((h / width) as usize).min(BINS - 1);
+                let difference = samples.values[i] - samples.values[j];
+                distances[bin] += h;
+                semivariances[bin] += (difference.x.powi(2) + difference.y.powi(2)) / 4.;
+                counts[bin] += 1;
+            }
+        }
+        let empirical = (0..BINS)
+            .filter(|&bin| counts[bin] > 0)
+            .map(|bin| {
+                let count = counts[bin] as f64;
+                (distances[bin] / count, semivariances[bin] / count, count)
+            })
+            .collect::<Vec<_>>();
+        if empirical.len() < 2 {
+            return Err(TooFewVelocities(n).into());
+        }
+        let mut best: Option<(f64, Variogram)> = None;
+        for k in 1..41 {
+            let range = max_lag * k as f64 / 20.;
+            let variogram = fit_with_range(&empirical, range);
+            let error = empirical
+                .iter()
+                .map(|&(h, gamma, count)| count * (variogram.semivariance(h) - gamma).powi(2))
+                .sum::<f64>();
+            if best.map(|(e, _)| error < e).unwrap_or(true) {
+                best = Some((error, variogram));
+            }
+        }
+        Ok(best.unwrap().1)
+    }
+
+    /// Returns the modelled semivariance at a distance.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::interpolate::Variogram;
+    /// let variogram = Variogram { nugget: 1., range: 10., sill: 2. };
+    /// assert_eq!(1., variogram.semivariance(0.));
+    /// assert!((variogram.semivariance(10.) - (1. + 2. * 0.95)).abs() < 0.01);
+    /// ```
+    pub fn semivariance(&self, distance: f64) -> f64 {
+        self.nugget + self.sill * (1. - (-3. * distance / self.range).exp())
+    }
+
+    /// Ordinary kriging of each velocity component from the nearest samples.
+    ///
+    /// Returns `None` if the kriging system is singular.
+    fn krige(&self, samples: &Samples, x: f64, y: f64, neighbors: usize) -> Option<(Vector, f64)> {
+        use nalgebra::{DMatrix, DVector};
+
+        let nearest = samples.tree.nearest_k(&[x, y, 0.], neighbors.max(1));
+        let k = nearest.len();
+        let mut a = DMatrix::<f64>::zeros(k + 1, k + 1);
+        let mut b = DVector::<f64>::zeros(k + 1);
+        for (row, &(i, distance2)) in nearest.iter().enumerate() {
+            for (col, &(j, _)) in nearest.iter().enumerate() {
+                a[(row, col)] = if i == j {
+                    0.
+                } else {
+                    self.semivariance(distance(&samples.positions[i], &samples.positions[j]))
+                };
+            }
+            a[(row, k)] = 1.;
+            a[(k, row)] = 1.;
+            b[row] = if distance2 == 0. {
+                0.
+            } else {
+                self.semivariance(distance2.sqrt())
+            };
+        }
+        b[k] = 1.;
+        let solution = a.try_inverse()? * &b;
+        let mut velocity = Vector::default();
+        let mut variance = solution[k];
+        for (row, &(i, _)) in nearest.iter().enumerate() {
+            velocity = velocity + samples.values[i] * solution[row];
+            variance += solution[row] * b[row];
+        }
+        Some((velocity, variance.max(0.).sqrt()))
+    }
+}
+
+impl Field {
+    /// Reads a field from a JSON file.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Field, Error> {
+        super::read_json(path)
+    }
+
+    /// Writes this field to a JSON file.
+    pub fn to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        super::write_json(self, path)
+    }
+
+    /// Writes this field as csv, one node per line.
+    pub fn to_csv<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        writeln!(writer, "x,y,vx,vy,vz,vxy,distance,uncertainty")?;
+        for node in self.nodes.iter() {
+            match node.velocity {
+                Some(velocity) => {
+                    writeln!(
+                        writer,
+                        "{},{},{},{},{},{},{},{}",
+                        node.x,
+                        node.y,
+                        velocity.x,
+                        velocity.y,
+                        velocity.z,
+                        velocity.xy(),
+                        node.distance,
+                        string_from_option(node.uncertainty)
+                    )?
+                }
+                None => writeln!(writer, "{},{},,,,,{},", node.x, node.y, node.distance)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the node at a row and column.
+    pub fn node(&self, row: usize, col: usize) -> Option<&Node> {
+        if row < self.rows && col < self.cols {
+            self.nodes.get(row * self.cols + col)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Method, Error> {
+        match s {
+            "idw" => Ok(Method::Idw { power: 2. }),
+            "natural-neighbor" => Ok(Method::NaturalNeighbor),
+            "kriging" => Ok(Method::Kriging),
+            _ => Err(InvalidMethod(s.to_string()).into()),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Method::Idw { power } => write!(f, "idw (power {})", power),
+            Method::NaturalNeighbor => write!(f, "natural-neighbor"),
+            Method::Kriging => write!(f, "kriging"),
+        }
+    }
+}
+
+/// Fits the nugget and sill of an exponential variogram with a fixed range.
+fn fit_with_range(empirical: &[(f64, f64, f64)], range: f64) -> Variogram {
+    let f = |h: f64| 1. - (-3. * h / range).exp();
+    let (mut sw, mut swf, mut swff, mut swg, mut swfg) = (0., 0., 0., 0., 0.);
+    for &(h, gamma, w) in empirical.iter() {
+        let fh = f(h);
+        sw += w;
+        swf += w * fh;
+        swff += w * fh * fh;
+        swg += w * gamma;
+        swfg += w * fh * gamma;
+    }
+    let determinant = sw * swff - swf * swf;
+    let (mut nugget, mut sill) = if determinant.abs() > 1e-12 {
+        (
+            (swff * swg - swf * swfg) / determinant,
+            (sw * swfg - swf * swg) / determinant,
+        )
+    } else {
+        (0., swfg / swff.max(1e-12))
+    };
+    if nugget < 0. {
+        nugget = 0.;
+        sill = swfg / swff.max(1e-12);
+    }
+    if sill < 0. {
+        sill = 0.;
+        nugget = swg / sw;
+    }
+    Variogram {
+        nugget: nugget,
+        range: range,
+        sill: sill,
+    }
+}
+
+fn distance(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    /// Creates a velocity file with a velocity at the center of each 10 m cell.
+    fn velocity_file(cells: &[(i64, i64, f64, f64)]) -> VelocityFile {
+        let velocities = cells
+            .iter()
+            .map(|&(row, col, vx, vy)| {
+                let (x, y) = (col as f64 * 10., row as f64 * 10.);
+                json!({
+                    "after_points": 300,
+                    "before_points": 300,
+                    "bounds": {"min_x": x, "min_y": y, "max_x": x + 10., "max_y": y + 10.},
+                    "center_of_gravity": {"x": x + 5., "y": y + 5., "z": 0.},
+                    "col": col,
+                    "datetime": "2017-06-01T12:00:00Z",
+                    "grid_size": 10.,
+                    "iterations": 10,
+                    "row": row,
+                    "unit": "m/h",
+                    "velocity": {"x": vx, "y": vy, "z": 0.},
+                    "x": x,
+                    "y": y
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(json!({
+            "version": 2,
+            "geometry": {"cell_size": 10., "origin_x": 0., "origin_y": 0.},
+            "velocities": velocities
+        })).unwrap()
+    }
+
+    fn block(f: fn(i64, i64) -> (f64, f64)) -> VelocityFile {
+        let mut cells = Vec::new();
+        for row in 0..4 {
+            for col in 0..4 {
+                let (vx, vy) = f(row, col);
+                cells.push((row, col, vx, vy));
+            }
+        }
+        velocity_file(&cells)
+    }
+
+    fn methods() -> Vec<Method> {
+        vec![Method::Idw { power: 2. }, Method::NaturalNeighbor, Method::Kriging]
+    }
+
+    #[test]
+    fn uniform_field() {
+        let velocity_file = block(|_, _| (1., 2.));
+        for &method in methods().iter() {
+            let field = Interpolator::new()
+                .method(method)
+                .spacing(5.)
+                .interpolate(&velocity_file)
+                .unwrap();
+            assert_eq!(64, field.nodes.len());
+            for node in field.nodes.iter() {
+                let velocity = node.velocity.unwrap();
+                assert!((velocity.x - 1.).abs() < 1e-9, "{:?}: {:?}", method, node);
+                assert!((velocity.y - 2.).abs() < 1e-9, "{:?}: {:?}", method, node);
+            }
+        }
+    }
+
+    #[test]
+    fn max_distance() {
+        let velocity_file = velocity_file(&[(0, 0, 1., 0.), (9, 9, 3., 0.)]);
+        for &method in [Method::Idw { power: 2. }, Method::NaturalNeighbor].iter() {
+            let field = Interpolator::new()
+                .method(method)
+                .max_distance(15.)
+                .interpolate(&velocity_file)
+                .unwrap();
+            assert_eq!(100, field.nodes.len());
+            assert!(field.nodes.iter().any(|node| node.velocity.is_none()));
+            for node in field.nodes.iter() {
+                assert_eq!(
+                    node.distance <= 15.,
+                    node.velocity.is_some(),
+                    "{:?}: {:?}",
+                    method,
+                    node
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn kriging_is_exact() {
+        let velocity_file = block(|row, col| ((row * col) as f64, (row - col * col) as f64));
+        let field = Interpolator::new()
+            .method(Method::Kriging)
+            .interpolate(&velocity_file)
+            .unwrap();
+        assert!(field.variogram.is_some());
+        for velocity in velocity_file.velocities.iter() {
+            let node = field.nodes[velocity.row as usize * field.cols + velocity.col as usize];
+            assert_eq!(0., node.distance);
+            assert!((node.velocity.unwrap() - velocity.velocity).xy() < 1e-6);
+        }
+    }
+}
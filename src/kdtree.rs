@@ -0,0 +1,191 @@
+//! A simple, static kd-tree for nearest neighbor queries.
+
+use std::cmp::Ordering;
+
+/// A kd-tree over two- or three-dimensional points.
+///
+/// The tree is implicit: `indices` is ordered so that the median of every range is the splitting
+/// point for that range.
+#[derive(Debug)]
+pub struct KdTree {
+    dims: usize,
+    indices: Vec<usize>,
+    points: Vec<[f64; 3]>,
+}
+
+impl KdTree {
+    /// Creates a new kd-tree that uses the first `dims` coordinates of each point.
+    pub fn new(points: Vec<[f64; 3]>, dims: usize) -> KdTree {
+        assert!(dims == 2 || dims == 3);
+        let mut indices = (0..points.len()).collect::<Vec<_>>();
+        build(&points, &mut indices, 0, dims);
+        KdTree {
+            dims: dims,
+            indices: indices,
+            points: points,
+        }
+    }
+
+    /// Returns the index of, and squared distance to, the nearest point.
+    pub fn nearest(&self, query: &[f64; 3]) -> Option<(usize, f64)> {
+        self.nearest_k(query, 1).pop()
+    }
+
+    /// Returns the indices of, and squared distances to, the `k` nearest points, closest first.
+    pub fn nearest_k(&self, query: &[f64; 3], k: usize) -> Vec<(usize, f64)> {
+        let mut neighbors = Vec::with_capacity(k + 1);
+        if k > 0 {
+            self.search_k(query, k, 0, self.indices.len(), 0, &mut neighbors);
+        }
+        neighbors
+    }
+
+    /// Returns the indices of, and squared distances to, all points within `radius`.
+    pub fn within(&self, query: &[f64; 3], radius: f64) -> Vec<(usize, f64)> {
+        let mut neighbors = Vec::new();
+        self.search_radius(
+            query,
+            radius * radius,
+            0,
+            self.indices.len(),
+            0,
+            &mut neighbors,
+        );
+        neighbors.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
+        neighbors
+    }
+
+    fn distance2(&self, a: &[f64; 3], b: &[f64; 3]) -> f64 {
+        (0..self.dims).map(|d| (a[d] - b[d]).powi(2)).sum()
+    }
+
+    fn search_k(
+        &self,
+        query: &[f64; 3],
+        k: usize,
+        lo: usize,
+        hi: usize,
+        depth: usize,
+        neighbors: &mut Vec<(usize, f64)>,
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let index = self.indices[mid];
+        let point = &self.points[index];
+        let distance2 = self.distance2(query, point);
+        if neighbors.len() < k || distance2 < neighbors[neighbors.len() - 1].1 {
+            let position = neighbors
+                .iter()
+                .position(|&(_, d)| distance2 < d)
+                .unwrap_or(neighbors.len());
+            neighbors.insert(position, (index, distance2));
+            neighbors.truncate(k);
+        }
+        let axis = depth % self.dims;
+        let diff = query[axis] - point[axis];
+        let (first, second) = if diff < 0. {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.search_k(query, k, first.0, first.1, depth + 1, neighbors);
+        if neighbors.len() < k || diff * diff < neighbors[neighbors.len() - 1].1 {
+            self.search_k(query, k, second.0, second.1, depth + 1, neighbors);
+        }
+    }
+
+    fn search_radius(
+        &self,
+        query: &[f64; 3],
+        radius2: f64,
+        lo: usize,
+        hi: usize,
+        depth: usize,
+        neighbors: &mut Vec<(usize, f64)>,
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let index = self.indices[mid];
+        let point = &self.points[index];
+        let distance2 = self.distance2(query, point);
+        if distance2 <= radius2 {
+            neighbors.push((index, distance2));
+        }
+        let axis = depth % self.dims;
+        let diff = query[axis] - point[axis];
+        if diff < 0. || diff * diff <= radius2 {
+            self.search_radius(query, radius2, lo, mid, depth + 1, neighbors);
+        }
+        if diff >= 0. || diff * diff <= radius2 {
+            self.search_radius(query, radius2, mid + 1, hi, depth + 1, neighbors);
+        }
+    }
+}
+
+fn build(points: &[[f64; 3]], indices: &mut [usize], depth: usize, dims: usize) {
+    if indices.len() <= 1 {
+        return;
+    }
+    let axis = depth % dims;
+    indices.sort_by(|&a, &b| {
+        points[a][axis].partial_cmp(&points[b][axis]).unwrap_or(
+            Ordering::Equal,
+        )
+    });
+    let mid = indices.len() / 2;
+    let (left, right) = indices.split_at_mut(mid);
+    build(points, left, depth + 1, dims);
+    build(points, &mut right[1..], depth + 1, dims);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points() -> Vec<[f64; 3]> {
+        let mut state = 12345u64;
+        let mut random = || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 11) as f64 / (1u64 << 53) as f64 * 100.
+        };
+        (0..500).map(|_| [random(), random(), random()]).collect()
+    }
+
+    fn brute_force(points: &[[f64; 3]], query: &[f64; 3], k: usize, dims: usize) -> Vec<f64> {
+        let mut distances = points
+            .iter()
+            .map(|point| (0..dims).map(|d| (point[d] - query[d]).powi(2)).sum::<f64>())
+            .collect::<Vec<_>>();
+        distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        distances.truncate(k);
+        distances
+    }
+
+    #[test]
+    fn nearest_k_matches_brute_force() {
+        let points = points();
+        for &dims in [2, 3].iter() {
+            let tree = KdTree::new(points.clone(), dims);
+            for query in [[0., 0., 0.], [50., 50., 50.], [99., 1., 42.], [-10., 120., 7.]].iter() {
+                for &k in [1, 5, 17].iter() {
+                    let neighbors = tree.nearest_k(query, k);
+                    let expected = brute_force(&points, query, k, dims);
+                    assert_eq!(expected.len(), neighbors.len());
+                    for (&(index, distance2), expected) in neighbors.iter().zip(expected) {
+                        assert_eq!(expected, distance2);
+                        let actual = (0..dims)
+                            .map(|d| (points[index][d] - query[d]).powi(2))
+                            .sum::<f64>();
+                        assert_eq!(actual, distance2);
+                    }
+                }
+                let nearest = tree.nearest(query).unwrap();
+                assert_eq!(brute_force(&points, query, 1, dims)[0], nearest.1);
+            }
+        }
+    }
+}
@@ -6,18 +6,39 @@ extern crate las;
 #[macro_use]
 extern crate log;
 extern crate nalgebra;
+extern crate num_cpus;
+extern crate rand;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 
+pub mod checkpoint;
+pub mod clean;
+pub mod displacement;
+pub mod flux;
+pub mod geometry;
+pub mod interpolate;
+mod kdtree;
+pub mod profile;
+pub mod progress;
+pub mod quadtree;
+pub mod registration;
+mod scheduler;
+pub mod stats;
+pub mod strain;
+pub mod timeseries;
+pub mod tracking;
 pub mod velocities;
 mod vector;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use failure::Error;
 use las::Point;
 use nalgebra::{Dynamic, MatrixMN, MatrixN, Projective3, U3, U4};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 use std::path::Path;
 pub use vector::Vector;
@@ -85,6 +106,18 @@ pub fn string_from_matrix(matrix: &MatrixN<f64, U4>) -> String {
     string
 }
 
+/// Formats an optional value for csv, as an empty string if there is no value.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!("1.5", ape::string_from_option(Some(1.5)));
+/// assert_eq!("", ape::string_from_option(None::<f64>));
+/// ```
+pub fn string_from_option<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
 /// Turns las points into a matrix.
 pub fn matrix_from_points(points: &Vec<Point>) -> MatrixMN<f64, Dynamic, U3> {
     let mut matrix = MatrixMN::<f64, Dynamic, U3>::zeros(points.len());
@@ -106,17 +139,59 @@ pub fn center_of_gravity(matrix: &MatrixMN<f64, Dynamic, U3>) -> Vector {
 }
 
 /// Calculates a date time from a path.
+///
+/// The file name must start with the date and time as `YYMMDD_HHMMSS`.
+///
+/// # Examples
+///
+/// ```
+/// use ape::datetime_from_path;
+/// let datetime = datetime_from_path("velocities/170601_120000.json").unwrap();
+/// assert_eq!("2017-06-01T12:00:00+00:00", datetime.to_rfc3339());
+/// assert!(datetime_from_path("notes.json").is_err());
+/// ```
 pub fn datetime_from_path<P: AsRef<Path>>(path: P) -> Result<DateTime<Utc>, Error> {
     use chrono::TimeZone;
 
-    if let Some(file_stem) = path.as_ref().file_stem().map(|s| s.to_string_lossy()) {
-        Utc.datetime_from_str(&file_stem[0..13], "%y%m%d_%H%M%S")
-            .map_err(Error::from)
-    } else {
-        Err(DateTimeFromPath(path.as_ref().display().to_string()).into())
+    match path.as_ref().file_stem().map(|s| s.to_string_lossy()) {
+        Some(ref file_stem) if file_stem.len() >= 13 && file_stem.is_char_boundary(13) => {
+            Utc.datetime_from_str(&file_stem[0..13], "%y%m%d_%H%M%S")
+                .map_err(Error::from)
+        }
+        _ => Err(DateTimeFromPath(path.as_ref().display().to_string()).into()),
     }
 }
 
+/// Returns the length of a duration in seconds, to the millisecond.
+pub(crate) fn seconds(duration: Duration) -> f64 {
+    duration.num_milliseconds() as f64 / 1000.
+}
+
+/// Returns a duration of some seconds, rounded to the millisecond.
+pub(crate) fn duration(seconds: f64) -> Duration {
+    Duration::milliseconds((seconds * 1000.).round() as i64)
+}
+
+/// Reads a value from a JSON file.
+pub(crate) fn read_json<T: DeserializeOwned, P: AsRef<Path>>(path: P) -> Result<T, Error> {
+    use std::fs::File;
+    use std::io::BufReader;
+
+    let reader = BufReader::new(File::open(path)?);
+    serde_json::from_reader(reader).map_err(Error::from)
+}
+
+/// Writes a value to a JSON file.
+pub(crate) fn write_json<T: Serialize, P: AsRef<Path>>(value: &T, path: P) -> Result<(), Error> {
+    use std::fs::File;
+    use std::io::{BufWriter, Write};
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    Ok(())
+}
+
 /// Returns the magic bucket configuration for the three matrices.
 pub fn magic_bucket_config(
     sop: &Projective3<f64>,
@@ -1,15 +1,25 @@
 extern crate ape;
+extern crate chrono;
 #[macro_use]
 extern crate clap;
 extern crate cpd;
 extern crate env_logger;
+#[macro_use]
+extern crate log;
 extern crate serde_json;
 
-use ape::velocities;
-use clap::App;
+use ape::{registration, string_from_option, velocities};
+use ape::clean::Cleaner;
+use ape::interpolate::{Field, Interpolator, Method};
+use ape::progress::{Json, Terminal};
+use ape::strain::StrainField;
+use clap::{App, ArgMatches};
 use cpd::{Normalize, Runner};
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader};
+use std::process;
+use std::time::Duration;
 
 const FORMAT_STR: &'static str = "%y%m%d_%H%M%S";
 
@@ -65,12 +75,55 @@ fn main() {
             serde_json::to_string_pretty(&ape::magic_bucket_config(&sop, &adjustment, &pop))
                 .unwrap()
         );
+    } else if let Some(matches) = matches.subcommand_matches("flux") {
+        use ape::flux::{Gate, NoVelocities, Thickness};
+        use ape::geometry::Polyline;
+        use std::path::Path;
+
+        let gate = Gate::new(
+            Polyline::from_path(matches.value_of("GATE").unwrap()).unwrap(),
+            Thickness::from_path(matches.value_of("THICKNESS").unwrap()).unwrap(),
+        ).spacing(value_t!(matches, "spacing", f64).ok())
+            .ratio(value_t!(matches, "ratio", f64).unwrap_or(1.))
+            .max_distance(value_t!(matches, "max-distance", f64).ok())
+            .include_flagged(matches.is_present("include-flagged"));
+        let mut paths = velocity_paths(matches.values_of("INFILES").unwrap())
+            .into_iter()
+            .map(|path| (ape::datetime_from_path(&path).unwrap(), path))
+            .collect::<Vec<_>>();
+        paths.sort();
+        println!("datetime,source,flux,uncertainty,coverage,unit");
+        for (datetime, path) in paths {
+            let flux = match gate.flux(&velocities::VelocityFile::from_path(&path).unwrap()) {
+                Ok(flux) => flux,
+                Err(ref err) if err.downcast_ref::<NoVelocities>().is_some() => {
+                    warn!("Skipping {}, it has no usable velocities", path.display());
+                    continue;
+                }
+                Err(err) => panic!("{}", err),
+            };
+            println!(
+                "{},{},{},{},{},{}",
+                datetime.to_rfc3339(),
+                path.display(),
+                flux.flux,
+                flux.uncertainty,
+                flux.coverage,
+                flux.unit
+            );
+            if let Some(directory) = matches.value_of("stations") {
+                let outfile = Path::new(directory).join(format!(
+                    "{}-stations.csv",
+                    path.file_stem().unwrap().to_string_lossy()
+                ));
+                flux.stations_to_csv(File::create(outfile).unwrap())
+                    .unwrap();
+            }
+        }
     } else if let Some(matches) = matches.subcommand_matches("cpd") {
-        let rigid = Runner::new()
-            .sigma2(value_t!(matches, "sigma2", f64).ok())
-            .normalize(Normalize::SameScale)
-            .rigid()
-            .scale(false);
+        let sigma2 = value_t!(matches, "sigma2", f64).ok();
+        let runner = || Runner::new().sigma2(sigma2).normalize(Normalize::SameScale);
+        let rigid = runner().rigid().scale(false);
         if let Some(matches) = matches.subcommand_matches("simple") {
             let fixed = ape::matrix_from_las_path(matches.value_of("FIXED").unwrap()).unwrap();
             let moving = ape::matrix_from_las_path(matches.value_of("MOVING").unwrap()).unwrap();
@@ -85,24 +138,322 @@ fn main() {
             if let Some(matches) = matches.subcommand_matches("create") {
                 let before = matches.value_of("BEFORE").unwrap();
                 let after = matches.value_of("AFTER").unwrap();
-                let grid_size = value_t!(matches, "grid-size", i64).unwrap_or(100);
-                let grid = velocities::Builder::new(before, after, grid_size)
-                    .unwrap()
+                let grid_size = value_t!(matches, "grid-size", f64).unwrap_or(100.);
+                let stride = value_t!(matches, "stride", f64).unwrap_or(grid_size);
+                let mut builder = or_exit(velocities::Builder::new(before, after, grid_size)
+                    .and_then(|builder| builder.stride(stride)))
+                    .origin(
+                        value_t!(matches, "origin-x", f64).unwrap_or(0.),
+                        value_t!(matches, "origin-y", f64).unwrap_or(0.),
+                    )
                     .min_points(value_t!(matches, "min-points", usize).unwrap_or(250))
                     .ngrow(value_t!(matches, "ngrow", usize).unwrap_or(1))
-                    .into_grid();
-                let mut file = File::create(matches.value_of("OUTFILE").unwrap()).unwrap();
-                let velocities =
-                    grid.calculate_velocities(value_t!(matches, "threads", usize).ok(), rigid)
-                        .into_iter()
-                        .filter_map(|v| v.ok())
-                        .collect::<Vec<_>>();
-                let string = serde_json::to_string(&velocities).unwrap();
-                write!(file, "{}", string).unwrap();
-            } else if let Some(matches) = matches.subcommand_matches("to-csv") {
-                let infile = File::open(matches.value_of("INFILE").unwrap()).unwrap();
-                let velocities: Vec<velocities::Velocity> = serde_json::from_reader(infile)
+                    .grow_healthy(matches.is_present("grow-healthy"))
+                    .overlap_tolerance(
+                        value_t!(matches, "overlap-tolerance", f64).unwrap_or(1.),
+                    )
+                    .bootstrap(value_t!(matches, "bootstrap", usize).unwrap_or(0))
+                    .seed(value_t!(matches, "seed", u64).unwrap_or(0))
+                    .unit(
+                        matches
+                            .value_of("unit")
+                            .unwrap_or("m/h")
+                            .parse::<velocities::Unit>()
+                            .unwrap(),
+                    );
+                if let Some(prior) = matches.value_of("prior") {
+                    builder = builder.prior(&velocities::VelocityFile::from_path(prior).unwrap());
+                }
+                if let Ok(gradient) = value_t!(matches, "refine-gradient", f64) {
+                    builder = builder.refine(
+                        value_t!(matches, "min-grid-size", f64).unwrap_or(grid_size / 4.),
+                        gradient,
+                    );
+                }
+                let mut grid = builder.into_grid();
+                if let Some(checkpoint) = matches.value_of("checkpoint") {
+                    grid = grid.checkpoint(
+                        checkpoint,
+                        matches.value_of("method").unwrap_or("cpd"),
+                    ).unwrap();
+                }
+                grid = match matches.value_of("progress") {
+                    Some("terminal") => grid.progress(Terminal::new(Duration::from_secs(1))),
+                    Some("json") => grid.progress(Json::new(Duration::from_secs(10))),
+                    _ => grid,
+                };
+                let geometry = grid.geometry();
+                let seed = grid.seed();
+                let registration = registration::from_name(
+                    matches.value_of("method").unwrap_or("cpd"),
+                    rigid,
+                    runner().nonrigid(),
+                ).unwrap();
+                let results = grid.calculate_velocities(
+                    value_t!(matches, "threads", usize).ok(),
+                    registration,
+                );
+                let mut velocity_file = velocities::VelocityFile::from_results(geometry, results);
+                velocity_file.seed = Some(seed);
+                velocity_file
+                    .to_path(matches.value_of("OUTFILE").unwrap())
+                    .unwrap();
+            } else if let Some(matches) = matches.subcommand_matches("consolidate") {
+                ape::checkpoint::consolidate(matches.value_of("CHECKPOINT").unwrap())
+                    .unwrap()
+                    .to_path(matches.value_of("OUTFILE").unwrap())
+                    .unwrap();
+            } else if let Some(matches) = matches.subcommand_matches("failures") {
+                use std::collections::BTreeMap;
+
+                let velocity_file =
+                    velocities::VelocityFile::from_path(matches.value_of("INFILE").unwrap())
+                        .unwrap();
+                if matches.is_present("csv") {
+                    println!(
+                        "min_x,min_y,max_x,max_y,grid_size,level,row,col,before_points,\
+                         after_points,kind,iterations,sigma2,rms,message"
+                    );
+                    for failure in velocity_file.failures {
+                        println!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},\"{}\"",
+                                 failure.bounds.min_x,
+                                 failure.bounds.min_y,
+                                 failure.bounds.max_x,
+                                 failure.bounds.max_y,
+                                 failure.grid_size,
+                                 failure.level,
+                                 failure.row,
+                                 failure.col,
+                                 failure.before_points,
+                                 failure.after_points,
+                                 failure.kind,
+                                 string_from_option(failure.iterations),
+                                 string_from_option(failure.sigma2),
+                                 string_from_option(failure.rms),
+                                 failure.message.replace('"', "'"),
+                                 );
+                    }
+                } else {
+                    let mut kinds = BTreeMap::new();
+                    for failure in velocity_file.failures.iter() {
+                        let entry = kinds.entry(failure.kind).or_insert_with(|| {
+                            (0, failure.bounds)
+                        });
+                        entry.0 += 1;
+                        entry.1.min_x = entry.1.min_x.min(failure.bounds.min_x);
+                        entry.1.min_y = entry.1.min_y.min(failure.bounds.min_y);
+                        entry.1.max_x = entry.1.max_x.max(failure.bounds.max_x);
+                        entry.1.max_y = entry.1.max_y.max(failure.bounds.max_y);
+                    }
+                    println!(
+                        "{} velocities, {} failures",
+                        velocity_file.velocities.len(),
+                        velocity_file.failures.len()
+                    );
+                    for (kind, (count, bounds)) in kinds {
+                        println!(
+                            "{}: {} cells, within ({}, {}) to ({}, {})",
+                            kind,
+                            count,
+                            bounds.min_x,
+                            bounds.min_y,
+                            bounds.max_x,
+                            bounds.max_y
+                        );
+                        let iterations = velocity_file
+                            .failures
+                            .iter()
+                            .filter(|failure| failure.kind == kind)
+                            .filter_map(|failure| failure.iterations)
+                            .collect::<Vec<_>>();
+                        if let (Some(min), Some(max)) =
+                            (iterations.iter().min(), iterations.iter().max())
+                        {
+                            println!("    after {} to {} iterations", min, max);
+                        }
+                    }
+                    println!();
+                    println!(
+                        "Map (north up): . calculated, c not converged, r registration error, \
+                         p too few points, ! panic"
+                    );
+                    for row in velocity_file.status_map() {
+                        println!("{}", row);
+                    }
+                }
+            } else if let Some(matches) = matches.subcommand_matches("clean") {
+                let mut velocity_file =
+                    velocities::VelocityFile::from_path(matches.value_of("INFILE").unwrap())
+                        .unwrap();
+                let cleaner = Cleaner::new()
+                    .median(threshold(matches, "median", 2.))
+                    .epsilon(value_t!(matches, "epsilon", f64).unwrap_or(0.))
+                    .coherence(threshold(matches, "coherence", 0.5))
+                    .z_score(threshold(matches, "z-score", 3.))
+                    .neighborhood(value_t!(matches, "neighborhood", f64).unwrap_or(1.5))
+                    .min_neighbors(value_t!(matches, "min-neighbors", usize).unwrap_or(3));
+                let flagged = cleaner.clean(&mut velocity_file);
+                println!(
+                    "{} of {} velocities flagged",
+                    flagged,
+                    velocity_file.velocities.len()
+                );
+                velocity_file
+                    .to_path(matches.value_of("OUTFILE").unwrap())
+                    .unwrap();
+            } else if let Some(matches) = matches.subcommand_matches("interpolate") {
+                let velocity_file =
+                    velocities::VelocityFile::from_path(matches.value_of("INFILE").unwrap())
+                        .unwrap();
+                let mut method = matches
+                    .value_of("method")
+                    .unwrap_or("idw")
+                    .parse::<Method>()
+                    .unwrap();
+                if let Method::Idw { ref mut power } = method {
+                    *power = value_t!(matches, "power", f64).unwrap_or(2.);
+                }
+                let field = Interpolator::new()
+                    .method(method)
+                    .neighbors(value_t!(matches, "neighbors", usize).unwrap_or(12))
+                    .max_distance(value_t!(matches, "max-distance", f64).ok())
+                    .spacing(value_t!(matches, "spacing", f64).ok())
+                    .include_flagged(matches.is_present("include-flagged"))
+                    .interpolate(&velocity_file)
+                    .unwrap();
+                let outfile = matches.value_of("OUTFILE").unwrap();
+                if matches.is_present("csv") {
+                    field.to_csv(File::create(outfile).unwrap()).unwrap();
+                } else {
+                    field.to_path(outfile).unwrap();
+                }
+            } else if let Some(matches) = matches.subcommand_matches("strain") {
+                let field = Field::from_path(matches.value_of("INFILE").unwrap()).unwrap();
+                let strain = StrainField::new(&field);
+                let outfile = matches.value_of("OUTFILE").unwrap();
+                if matches.is_present("csv") {
+                    strain.to_csv(File::create(outfile).unwrap()).unwrap();
+                } else {
+                    strain.to_path(outfile).unwrap();
+                }
+            } else if let Some(matches) = matches.subcommand_matches("cumulative") {
+                use ape::displacement::Integrator;
+                use ape::timeseries::Store;
+
+                let mut store = Store::new();
+                for path in velocity_paths(matches.values_of("INFILES").unwrap()) {
+                    store.ingest(path).unwrap();
+                }
+                let displacements = Integrator::new()
+                    .max_gap(value_t!(matches, "max-gap", f64).ok().map(|h| h * 3600.))
+                    .include_flagged(matches.is_present("include-flagged"))
+                    .integrate(&store);
+                let outfile = matches.value_of("OUTFILE").unwrap();
+                if matches.is_present("csv") {
+                    displacements.to_csv(File::create(outfile).unwrap()).unwrap();
+                } else {
+                    displacements.to_path(outfile).unwrap();
+                }
+            } else if let Some(matches) = matches.subcommand_matches("track") {
+                use ape::geometry::{self, Polyline};
+                use ape::tracking::{self, Tracer};
+
+                let mut velocity_files = velocity_paths(matches.values_of("INFILES").unwrap())
+                    .into_iter()
+                    .map(|path| {
+                        (
+                            ape::datetime_from_path(&path).unwrap(),
+                            velocities::VelocityFile::from_path(&path).unwrap(),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                velocity_files.sort_by_key(|&(datetime, _)| datetime);
+                let seeds = if let Some(seeds) = matches.value_of("seeds") {
+                    geometry::points_from_path(seeds).unwrap()
+                } else {
+                    let bounds = match matches.value_of("bbox") {
+                        Some(bbox) => bounds(bbox),
+                        None => {
+                            velocity_files
+                                .first()
+                                .and_then(|&(_, ref velocity_file)| velocity_file.bounds())
+                                .expect("No velocities to seed from")
+                        }
+                    };
+                    tracking::seed_grid(&bounds, value_t!(matches, "seed-spacing", f64).unwrap())
+                };
+                let trajectories = Tracer::new()
+                    .step(value_t!(matches, "step", f64).unwrap_or(1.) * 3600.)
+                    .start(matches.value_of("start").map(datetime))
+                    .end(matches.value_of("end").map(datetime))
+                    .max_distance(value_t!(matches, "max-distance", f64).ok())
+                    .target(matches.value_of("target").map(
+                        |path| Polyline::from_path(path).unwrap(),
+                    ))
+                    .include_flagged(matches.is_present("include-flagged"))
+                    .track(&velocity_files, &seeds)
                     .unwrap();
+                let outfile = matches.value_of("OUTFILE").unwrap();
+                if matches.is_present("csv") {
+                    trajectories.to_csv(File::create(outfile).unwrap()).unwrap();
+                } else {
+                    trajectories.to_path(outfile).unwrap();
+                }
+                if let Some(arrivals) = matches.value_of("arrivals") {
+                    trajectories
+                        .arrivals_to_csv(File::create(arrivals).unwrap())
+                        .unwrap();
+                }
+            } else if let Some(matches) = matches.subcommand_matches("timeseries") {
+                use ape::timeseries::{Query, Store};
+                use std::path::Path;
+
+                if let Some(matches) = matches.subcommand_matches("ingest") {
+                    let store_path = matches.value_of("STORE").unwrap();
+                    let mut store = if Path::new(store_path).exists() {
+                        Store::from_path(store_path).unwrap()
+                    } else {
+                        Store::new()
+                    };
+                    let mut ingested = 0;
+                    for path in velocity_paths(matches.values_of("INFILES").unwrap()) {
+                        if store.ingest(&path).unwrap() {
+                            ingested += 1;
+                        } else {
+                            println!("Skipping {}, already ingested", path.display());
+                        }
+                    }
+                    println!(
+                        "Ingested {} files, {} cells in store",
+                        ingested,
+                        store.series.len()
+                    );
+                    store.to_path(store_path).unwrap();
+                } else if let Some(matches) = matches.subcommand_matches("export") {
+                    use std::io;
+
+                    let store = Store::from_path(matches.value_of("STORE").unwrap()).unwrap();
+                    let mut query = Query::new();
+                    if let Some(cell) = matches.value_of("cell") {
+                        query = query.key(cell.parse().unwrap());
+                    }
+                    if let Some(bbox) = matches.value_of("bbox") {
+                        query = query.bounds(bounds(bbox));
+                    }
+                    if let Some(start) = matches.value_of("start") {
+                        query = query.start(datetime(start));
+                    }
+                    if let Some(end) = matches.value_of("end") {
+                        query = query.end(datetime(end));
+                    }
+                    let stdout = io::stdout();
+                    store.to_csv(&query, stdout.lock()).unwrap();
+                }
+            } else if let Some(matches) = matches.subcommand_matches("to-csv") {
+                let velocities =
+                    velocities::VelocityFile::from_path(matches.value_of("INFILE").unwrap())
+                        .unwrap()
+                        .velocities;
                 let max_iterations = matches.value_of("max-iterations").map(|s| {
                     s.parse::<usize>().unwrap()
                 });
@@ -112,7 +463,17 @@ fn main() {
                 let min_height = matches.value_of("min-height").map(
                     |s| s.parse::<f64>().unwrap(),
                 );
-                println!("x,y,z,grid_size,iterations,vx,vy,vz,vxy,v");
+                let max_rms = matches.value_of("max-rms").map(|s| s.parse::<f64>().unwrap());
+                let min_overlap = matches.value_of("min-overlap").map(|s| {
+                    s.parse::<f64>().unwrap()
+                });
+                let max_rotation = matches.value_of("max-rotation").map(|s| {
+                    s.parse::<f64>().unwrap()
+                });
+                let skip_flagged = matches.is_present("skip-flagged");
+                println!(
+                    "x,y,z,grid_size,iterations,vx,vy,vz,vxy,v,sigma2,rms,rotation,overlap,unit,flags"
+                );
                 for velocity in velocities {
                     if max_iterations.map(|m| velocity.iterations < m).unwrap_or(
                         true,
@@ -122,9 +483,13 @@ fn main() {
                             .unwrap_or(true) &&
                         min_height
                             .map(|m| velocity.center_of_gravity.z > m)
-                            .unwrap_or(true)
+                            .unwrap_or(true) &&
+                        passes(velocity.rms, max_rms, |v, m| v < m) &&
+                        passes(velocity.overlap, min_overlap, |v, m| v > m) &&
+                        passes(velocity.rotation, max_rotation, |v, m| v < m) &&
+                        velocity.is_included(!skip_flagged)
                     {
-                        println!("{},{},{},{},{},{},{},{},{},{}",
+                        println!("{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
                                  velocity.center_of_gravity.x,
                                  velocity.center_of_gravity.y,
                                  velocity.center_of_gravity.z,
@@ -135,70 +500,85 @@ fn main() {
                                  velocity.velocity.z,
                                  velocity.velocity.xy(),
                                  velocity.velocity.magnitude(),
+                                 string_from_option(velocity.sigma2),
+                                 string_from_option(velocity.rms),
+                                 string_from_option(velocity.rotation),
+                                 string_from_option(velocity.overlap),
+                                 velocity.unit,
+                                 velocity
+                                     .flags
+                                     .iter()
+                                     .map(|flag| flag.to_string())
+                                     .collect::<Vec<_>>()
+                                     .join("|"),
                                  );
                     }
                 }
-            } else if let Some(matches) = matches.subcommand_matches("line") {
-                use std::collections::HashMap;
-                use std::fs;
-                use ape::Vector;
+            } else if let Some(matches) = matches.subcommand_matches("profile") {
+                use ape::geometry::Polyline;
+                use ape::profile::{Profiler, Sampling};
+                use ape::stats::Statistics;
+                use std::io;
 
-                let directory = matches.value_of("DIRECTORY").unwrap();
-                let northing = matches
-                    .value_of("NORTHING")
-                    .unwrap()
-                    .parse::<f64>()
-                    .unwrap();
-                let mut map = HashMap::new();
-                for (velocity, datetime) in
-                    fs::read_dir(directory)
-                        .unwrap()
-                        .filter_map(|r| {
-                            r.ok().and_then(|dir_entry| if dir_entry
-                                .path()
-                                .extension()
-                                .map(|e| e == "json")
-                                .unwrap_or(false)
-                            {
-                                serde_json::from_reader::<_, Vec<velocities::Velocity>>(
-                                    File::open(dir_entry.path()).unwrap(),
-                                ).ok()
-                                    .map(
-                                        |v| (v, ape::datetime_from_path(dir_entry.path()).unwrap()),
-                                    )
-                            } else {
-                                None
-                            })
-                        })
-                        .flat_map(|(v, datetime)| {
-                            v.into_iter()
-                                .filter(|v| v.y == northing && v.grid_size == 100)
-                                .map(move |v| (v, datetime))
-                        })
-                {
-                    let entry = map.entry(velocity.x as i64).or_insert_with(Vec::new);
-                    entry.push((velocity, datetime));
-                }
-                println!("datetime,x,vx,vy,vz,vxy,v,dvx,dvy,dvz,dvxy,dv");
-                for (_, cell) in map {
-                    let mean = Vector::mean(&cell.iter().map(|&(ref v, _)| v.velocity).collect());
-                    for (velocity, datetime) in cell {
-                        println!("{},{},{},{},{},{},{},{},{},{},{},{}",
-                                 datetime,
-                                 velocity.x,
-                                 velocity.velocity.x,
-                                 velocity.velocity.y,
-                                 velocity.velocity.z,
-                                 velocity.velocity.xy(),
-                                 velocity.velocity.magnitude(),
-                                 velocity.velocity.x - mean.x,
-                                 velocity.velocity.y - mean.y,
-                                 velocity.velocity.z - mean.z,
-                                 velocity.velocity.xy() - mean.xy(),
-                                 velocity.velocity.magnitude() - mean.magnitude(),
-                                 )
+                let polyline = match matches.value_of("polyline") {
+                    Some(path) => Polyline::from_path(path).unwrap(),
+                    None => {
+                        Polyline::new(vec![
+                            point(matches.value_of("from").unwrap()),
+                            point(matches.value_of("to").unwrap()),
+                        ]).unwrap()
                     }
-
+                };
+                let mut sampling = matches
+                    .value_of("method")
+                    .unwrap_or("nearest")
+                    .parse::<Sampling>()
+                    .unwrap();
+                if let Sampling::Idw { ref mut power } = sampling {
+                    *power = value_t!(matches, "power", f64).unwrap_or(2.);
+                }
+                let mut velocity_files = velocity_paths(matches.values_of("INFILES").unwrap())
+                    .into_iter()
+                    .map(|path| {
+                        (
+                            ape::datetime_from_path(&path).unwrap(),
+                            velocities::VelocityFile::from_path(&path).unwrap(),
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                velocity_files.sort_by_key(|&(datetime, _)| datetime);
+                let profile = Profiler::new(polyline)
+                    .spacing(value_t!(matches, "spacing", f64).ok())
+                    .tolerance(value_t!(matches, "tolerance", f64).ok())
+                    .grid_size(value_t!(matches, "grid-size", f64).ok())
+                    .sampling(sampling)
+                    .unit(
+                        matches
+                            .value_of("unit")
+                            .unwrap_or("m/h")
+                            .parse::<velocities::Unit>()
+                            .unwrap(),
+                    )
+                    .include_flagged(matches.is_present("include-flagged"))
+                    .profile(&velocity_files)
+                    .unwrap();
+                let stdout = io::stdout();
+                profile.to_csv(stdout.lock()).unwrap();
+                let mut statistics = Statistics::new()
+                    .trim(value_t!(matches, "trim", f64).unwrap_or(0.1))
+                    .confidence(value_t!(matches, "confidence", f64).unwrap_or(0.95));
+                if let Some(values) = matches.value_of("percentiles") {
+                    statistics = statistics.percentiles(percentiles(values));
+                }
+                if let Some(summary) = matches.value_of("summary") {
+                    profile
+                        .summary_to_csv(&statistics, File::create(summary).unwrap())
+                        .unwrap();
+                }
+                if let Some(anomalies) = matches.value_of("anomalies") {
+                    profile
+                        .anomalies_to_csv(&statistics, File::create(anomalies).unwrap())
+                        .unwrap();
                 }
             }
         }
@@ -206,3 +586,104 @@ fn main() {
         panic!("Invalid command");
     }
 }
+
+/// Returns true if the value passes the filter, or if there is no value or no filter.
+fn passes<F: Fn(f64, f64) -> bool>(value: Option<f64>, filter: Option<f64>, f: F) -> bool {
+    match (value, filter) {
+        (Some(value), Some(filter)) => f(value, filter),
+        _ => true,
+    }
+}
+
+/// Returns a test threshold from the command line, `None` if the test is disabled with "off", or
+/// the default.
+fn threshold(matches: &ArgMatches, name: &str, default: f64) -> Option<f64> {
+    match matches.value_of(name) {
+        Some("off") => None,
+        Some(value) => Some(value.parse().unwrap()),
+        None => Some(default),
+    }
+}
+
+/// Returns the value of a result, or prints its error and exits.
+fn or_exit<T, E: fmt::Display>(result: Result<T, E>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(1)
+        }
+    }
+}
+
+/// Expands velocity file arguments, replacing each directory with the json files inside it.
+///
+/// Files in a directory whose names don't start with a date and time are skipped.
+fn velocity_paths<'a, I: Iterator<Item = &'a str>>(values: I) -> Vec<std::path::PathBuf> {
+    use std::fs;
+    use std::path::PathBuf;
+
+    let mut paths = Vec::new();
+    for value in values {
+        let path = PathBuf::from(value);
+        if path.is_dir() {
+            let mut entries = fs::read_dir(&path)
+                .unwrap()
+                .filter_map(|r| r.ok().map(|dir_entry| dir_entry.path()))
+                .filter(|p| p.extension().map(|e| e == "json").unwrap_or(false))
+                .filter(|p| if ape::datetime_from_path(p).is_ok() {
+                    true
+                } else {
+                    eprintln!("Skipping {}, its name has no date and time", p.display());
+                    false
+                })
+                .collect::<Vec<_>>();
+            entries.sort();
+            paths.extend(entries);
+        } else {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// Parses an RFC 3339 date and time from the command line.
+fn datetime(s: &str) -> chrono::DateTime<chrono::Utc> {
+    use chrono::{DateTime, Utc};
+    DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+}
+
+/// Parses a bounding box given as `min_x,min_y,max_x,max_y` on the command line.
+fn bounds(s: &str) -> ape::geometry::Bounds {
+    let numbers = s.split(',')
+        .map(|n| n.trim().parse::<f64>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(4, numbers.len(), "bbox should be min_x,min_y,max_x,max_y");
+    ape::geometry::Bounds {
+        min_x: numbers[0],
+        min_y: numbers[1],
+        max_x: numbers[2],
+        max_y: numbers[3],
+    }
+}
+
+/// Parses comma separated percentiles, each between 0 and 100, from the command line.
+fn percentiles(s: &str) -> Vec<f64> {
+    let numbers = s.split(',')
+        .map(|n| n.trim().parse::<f64>().unwrap())
+        .collect::<Vec<_>>();
+    assert!(
+        numbers.iter().all(|&n| n >= 0. && n <= 100.),
+        "percentiles should be between 0 and 100"
+    );
+    numbers
+}
+
+/// Parses a point given as `x,y` on the command line.
+fn point(s: &str) -> (f64, f64) {
+    let numbers = s.split(',')
+        .map(|n| n.trim().parse::<f64>().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(2, numbers.len(), "point should be x,y");
+    (numbers[0], numbers[1])
+}
@@ -0,0 +1,618 @@
+//! Velocity profiles along a polyline.
+//!
+//! Stations are placed at a regular spacing along the profile. At each station a velocity is
+//! either taken from the nearest cell whose center is within a tolerance, or interpolated with
+//! inverse distance weighting from the velocities within the tolerance.
+
+use Vector;
+use {seconds, string_from_option};
+use chrono::{DateTime, Utc};
+use failure::Error;
+use geometry::Polyline;
+use interpolate::Samples;
+use kdtree::KdTree;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use stats::{Statistics, Summary, Trend};
+use std::str::FromStr;
+use velocities::{Unit, VelocityFile};
+
+/// The sampling method name is not recognized.
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid sampling method: {}", _0)]
+pub struct InvalidSampling(String);
+
+/// How velocities are sampled at each station.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Sampling {
+    /// Use the velocity of the nearest cell center.
+    Nearest,
+
+    /// Inverse distance weighting with the given power.
+    Idw {
+        /// The power of the inverse distance.
+        power: f64,
+    },
+}
+
+/// The quantities summarized at each station.
+const QUANTITIES: [&'static str; 7] = ["vx", "vy", "vz", "vxy", "v", "along", "across"];
+
+/// Samples velocities along a polyline.
+#[derive(Clone, Debug)]
+pub struct Profiler {
+    grid_size: Option<f64>,
+    include_flagged: bool,
+    neighbors: usize,
+    polyline: Polyline,
+    sampling: Sampling,
+    spacing: Option<f64>,
+    tolerance: Option<f64>,
+    unit: Unit,
+}
+
+/// A velocity profile through time.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Profile {
+    /// The samples, sorted by distance along the profile and then by date and time.
+    pub samples: Vec<ProfileSample>,
+
+    /// The unit of the velocities.
+    pub unit: Unit,
+}
+
+/// A velocity at a station along a profile.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ProfileSample {
+    /// The horizontal velocity component along the profile.
+    pub along: f64,
+
+    /// The horizontal velocity component across the profile, positive to the left.
+    pub across: f64,
+
+    /// The date and time of the velocity file.
+    pub datetime: DateTime<Utc>,
+
+    /// The distance of the station along the profile.
+    pub distance: f64,
+
+    /// The size of the cell the velocity came from, for nearest sampling.
+    pub grid_size: Option<f64>,
+
+    /// The distance between the station and the nearest velocity.
+    pub offset: f64,
+
+    /// The velocity.
+    pub velocity: Vector,
+
+    /// The easting of the station.
+    pub x: f64,
+
+    /// The northing of the station.
+    pub y: f64,
+}
+
+impl Profiler {
+    /// Creates a new profiler along a polyline.
+    ///
+    /// By default stations are one cell size apart, each takes the velocity of the nearest cell
+    /// center within one cell size, and cells of every size are used.
+    pub fn new(polyline: Polyline) -> Profiler {
+        Profiler {
+            grid_size: None,
+            include_flagged: false,
+            neighbors: 12,
+            polyline: polyline,
+            sampling: Sampling::Nearest,
+            spacing: None,
+            tolerance: None,
+            unit: Unit::default(),
+        }
+    }
+
+    /// Sets the distance between stations, instead of the velocity file's cell size.
+    pub fn spacing<T: Into<Option<f64>>>(mut self, spacing: T) -> Profiler {
+        self.spacing = spacing.into();
+        self
+    }
+
+    /// Sets the sampling method.
+    pub fn sampling(mut self, sampling: Sampling) -> Profiler {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Sets the farthest a station can be from a velocity, instead of the velocity file's cell
+    /// size.
+    pub fn tolerance<T: Into<Option<f64>>>(mut self, tolerance: T) -> Profiler {
+        self.tolerance = tolerance.into();
+        self
+    }
+
+    /// Only uses cells of this size, or every cell if `None`.
+    pub fn grid_size<T: Into<Option<f64>>>(mut self, grid_size: T) -> Profiler {
+        self.grid_size = grid_size.into();
+        self
+    }
+
+    /// Sets the number of nearest velocities used by inverse distance weighting.
+    pub fn neighbors(mut self, neighbors: usize) -> Profiler {
+        self.neighbors = neighbors;
+        self
+    }
+
+    /// Sets the unit of the sampled velocities.
+    pub fn unit(mut self, unit: Unit) -> Profiler {
+        self.unit = unit;
+        self
+    }
+
+    /// Includes velocities flagged as outliers in the samples.
+    pub fn include_flagged(mut self, include_flagged: bool) -> Profiler {
+        self.include_flagged = include_flagged;
+        self
+    }
+
+    /// Samples every velocity file, returning a profile sorted by distance and then time.
+    ///
+    /// Fails if the station spacing isn't positive.
+    pub fn profile(
+        &self,
+        velocity_files: &[(DateTime<Utc>, VelocityFile)],
+    ) -> Result<Profile, Error> {
+        use std::cmp::Ordering;
+
+        let mut samples = Vec::new();
+        for &(datetime, ref velocity_file) in velocity_files {
+            samples.extend(self.sample(datetime, velocity_file)?);
+        }
+        samples.sort_by(|a, b| {
+            a.distance
+                .partial_cmp(&b.distance)
+                .unwrap_or(Ordering::Equal)
+                .then(a.datetime.cmp(&b.datetime))
+        });
+        Ok(Profile {
+            samples: samples,
+            unit: self.unit,
+        })
+    }
+
+    /// Samples one velocity file at the stations along the profile.
+    ///
+    /// Stations without a velocity within the tolerance are left out.
+    pub fn sample(
+        &self,
+        datetime: DateTime<Utc>,
+        velocity_file: &VelocityFile,
+    ) -> Result<Vec<ProfileSample>, Error> {
+        let velocities = velocity_file
+            .included(self.include_flagged)
+            .into_iter()
+            .filter(|v| {
+                self.grid_size
+                    .map(|grid_size| (v.grid_size - grid_size).abs() < 1e-6)
+                    .unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+        if velocities.is_empty() {
+            return Ok(Vec::new());
+        }
+        let cell_size = velocity_file.geometry.cell_size;
+        let tolerance = self.tolerance.unwrap_or(cell_size);
+        let values = velocities
+            .iter()
+            .map(|v| v.velocity * (self.unit.seconds() / v.unit.seconds()))
+            .collect::<Vec<_>>();
+        let centers = velocities
+            .iter()
+            .map(|v| {
+                let (x, y) = v.bounds.center();
+                [x, y, 0.]
+            })
+            .collect::<Vec<_>>();
+        let tree = KdTree::new(centers, 2);
+        let samples = match self.sampling {
+            Sampling::Nearest => None,
+            Sampling::Idw { .. } => Some(Samples::new(
+                velocities
+                    .iter()
+                    .map(|v| [v.center_of_gravity.x, v.center_of_gravity.y, 0.])
+                    .collect(),
+                values.clone(),
+            )),
+        };
+        let stations = self.polyline.stations(self.spacing.unwrap_or(cell_size))?;
+        Ok(stations
+            .into_iter()
+            .filter_map(|station| {
+                let (nearest, distance2) = tree.nearest(&[station.x, station.y, 0.])?;
+                let offset = distance2.sqrt();
+                if offset > tolerance {
+                    return None;
+                }
+                let (velocity, grid_size) = match (self.sampling, samples.as_ref()) {
+                    (Sampling::Idw { power }, Some(samples)) => {
+                        let (velocity, _) =
+                            samples.idw(station.x, station.y, power, self.neighbors);
+                        (velocity, None)
+                    }
+                    _ => (values[nearest], Some(velocities[nearest].grid_size)),
+                };
+                let (dx, dy) = station.direction;
+                Some(ProfileSample {
+                    along: velocity.x * dx + velocity.y * dy,
+                    across: -velocity.x * dy + velocity.y * dx,
+                    datetime: datetime,
+                    distance: station.distance,
+                    grid_size: grid_size,
+                    offset: offset,
+                    velocity: velocity,
+                    x: station.x,
+                    y: station.y,
+                })
+            })
+            .collect())
+    }
+}
+
+impl Profile {
+    /// Writes this profile as csv, one sample per line.
+    ///
+    /// The `d` columns are each sample's difference from the mean of all samples at its station.
+    pub fn to_csv<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        let mut stations = BTreeMap::new();
+        for sample in self.samples.iter() {
+            stations
+                .entry(station_key(sample.distance))
+                .or_insert_with(Vec::new)
+                .push(sample.velocity);
+        }
+        let means = stations
+            .into_iter()
+            .map(|(key, velocities)| (key, Vector::mean(&velocities)))
+            .collect::<BTreeMap<_, _>>();
+        writeln!(
+            writer,
+            "datetime,distance,x,y,vx,vy,vz,vxy,v,along,across,dvx,dvy,dvz,dvxy,dv,offset,\
+             grid_size,unit"
+        )?;
+        for sample in self.samples.iter() {
+            let velocity = sample.velocity;
+            let mean = means[&station_key(sample.distance)];
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                sample.datetime.to_rfc3339(),
+                sample.distance,
+                sample.x,
+                sample.y,
+                velocity.x,
+                velocity.y,
+                velocity.z,
+                velocity.xy(),
+                velocity.magnitude(),
+                sample.along,
+                sample.across,
+                velocity.x - mean.x,
+                velocity.y - mean.y,
+                velocity.z - mean.z,
+                velocity.xy() - mean.xy(),
+                velocity.magnitude() - mean.magnitude(),
+                sample.offset,
+                string_from_option(sample.grid_size),
+                self.unit
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes statistics over time for each quantity at each station as csv.
+    ///
+    /// The `trend_per_day` columns are in the velocity unit per day.
+    pub fn summary_to_csv<W: Write>(
+        &self,
+        statistics: &Statistics,
+        mut writer: W,
+    ) -> Result<(), Error> {
+        write!(
+            writer,
+            "distance,x,y,quantity,count,mean,median,mad,std,trimmed_mean"
+        )?;
+        for percentile in statistics.requested_percentiles() {
+            write!(writer, ",p{}", percentile)?;
+        }
+        writeln!(
+            writer,
+            ",trend_per_day,trend_per_day_low,trend_per_day_high,unit"
+        )?;
+        for (samples, summaries) in self.summaries(statistics) {
+            let station = samples[0];
+            for (quantity, summary) in QUANTITIES.iter().zip(summaries) {
+                write!(
+                    writer,
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    station.distance,
+                    station.x,
+                    station.y,
+                    quantity,
+                    summary.count,
+                    summary.mean,
+                    summary.median,
+                    summary.mad,
+                    string_from_option(summary.std),
+                    summary.trimmed_mean
+                )?;
+                for &(_, value) in summary.percentiles.iter() {
+                    write!(writer, ",{}", value)?;
+                }
+                let trend =
+                    |f: fn(&Trend) -> f64| string_from_option(summary.trend.as_ref().map(f));
+                writeln!(
+                    writer,
+                    ",{},{},{},{}",
+                    trend(|t| t.slope),
+                    trend(|t| t.slope_low),
+                    trend(|t| t.slope_high),
+                    self.unit
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes each sample's anomalies from its station's statistics as csv, one quantity of one
+    /// sample per line.
+    ///
+    /// The robust z-score is the distance from the median in median absolute deviations scaled
+    /// to standard deviations, and the detrended value is the residual from the station's trend.
+    pub fn anomalies_to_csv<W: Write>(
+        &self,
+        statistics: &Statistics,
+        mut writer: W,
+    ) -> Result<(), Error> {
+        writeln!(
+            writer,
+            "datetime,distance,x,y,quantity,value,median_anomaly,mean_anomaly,robust_z,\
+             detrended,unit"
+        )?;
+        let start = self.start();
+        for (samples, summaries) in self.summaries(statistics) {
+            for sample in samples {
+                let time = days(start, sample.datetime);
+                for (i, (quantity, summary)) in
+                    QUANTITIES.iter().zip(summaries.iter()).enumerate()
+                {
+                    let value = sample.quantity(i);
+                    writeln!(
+                        writer,
+                        "{},{},{},{},{},{},{},{},{},{},{}",
+                        sample.datetime.to_rfc3339(),
+                        sample.distance,
+                        sample.x,
+                        sample.y,
+                        quantity,
+                        value,
+                        value - summary.median,
+                        value - summary.mean,
+                        string_from_option(summary.robust_z(value)),
+                        string_from_option(summary.trend.map(|trend| value - trend.at(time))),
+                        self.unit
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns each station's samples and the summary of each quantity, in distance order.
+    fn summaries(&self, statistics: &Statistics) -> Vec<(Vec<&ProfileSample>, Vec<Summary>)> {
+        let start = self.start();
+        let mut stations = BTreeMap::new();
+        for sample in self.samples.iter() {
+            stations
+                .entry(station_key(sample.distance))
+                .or_insert_with(Vec::new)
+                .push(sample);
+        }
+        stations
+            .into_iter()
+            .map(|(_, samples)| {
+                let times = samples
+                    .iter()
+                    .map(|sample| days(start, sample.datetime))
+                    .collect::<Vec<_>>();
+                let summaries = (0..QUANTITIES.len())
+                    .map(|i| {
+                        let values = samples.iter().map(|s| s.quantity(i)).collect::<Vec<_>>();
+                        statistics.summarize(&times, &values).unwrap()
+                    })
+                    .collect();
+                (samples, summaries)
+            })
+            .collect()
+    }
+
+    /// Returns the date and time of the earliest sample, which is time zero for trends.
+    fn start(&self) -> DateTime<Utc> {
+        self.samples
+            .iter()
+            .map(|sample| sample.datetime)
+            .min()
+            .unwrap_or_else(Utc::now)
+    }
+}
+
+impl ProfileSample {
+    /// Returns one of the summarized quantities, by its index in `QUANTITIES`.
+    fn quantity(&self, index: usize) -> f64 {
+        match index {
+            0 => self.velocity.x,
+            1 => self.velocity.y,
+            2 => self.velocity.z,
+            3 => self.velocity.xy(),
+            4 => self.velocity.magnitude(),
+            5 => self.along,
+            _ => self.across,
+        }
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Sampling, Error> {
+        match s {
+            "nearest" => Ok(Sampling::Nearest),
+            "idw" => Ok(Sampling::Idw { power: 2. }),
+            _ => Err(InvalidSampling(s.to_string()).into()),
+        }
+    }
+}
+
+impl fmt::Display for Sampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Sampling::Nearest => write!(f, "nearest"),
+            Sampling::Idw { .. } => write!(f, "idw"),
+        }
+    }
+}
+
+/// Returns the number of days from one date and time to another.
+fn days(start: DateTime<Utc>, datetime: DateTime<Utc>) -> f64 {
+    seconds(datetime.signed_duration_since(start)) / 86_400.
+}
+
+/// Identifies a station by its distance, to the millimeter.
+fn station_key(distance: f64) -> i64 {
+    (distance * 1000.).round() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    /// Creates a velocity file with the same velocity in three 10 m cells along y = 5 and one
+    /// grown 20 m cell at the end of the line.
+    fn velocity_file(datetime: &str, vx: f64, vy: f64) -> (DateTime<Utc>, VelocityFile) {
+        let velocity = |x: f64, size: f64| {
+            json!({
+                "after_points": 300,
+                "before_points": 300,
+                "bounds": {"min_x": x, "min_y": 0., "max_x": x + size, "max_y": size},
+                "center_of_gravity": {"x": x + size / 2., "y": size / 2., "z": 0.},
+                "datetime": datetime,
+                "grid_size": size,
+                "iterations": 10,
+                "unit": "m/day",
+                "velocity": {"x": vx, "y": vy, "z": 0.},
+                "x": x,
+                "y": 0.
+            })
+        };
+        let velocity_file = serde_json::from_value(json!({
+            "version": 2,
+            "geometry": {"cell_size": 10., "origin_x": 0., "origin_y": 0.},
+            "velocities": [
+                velocity(0., 10.),
+                velocity(10., 10.),
+                velocity(20., 10.),
+                velocity(40., 20.)
+            ]
+        })).unwrap();
+        (datetime.parse().unwrap(), velocity_file)
+    }
+
+    fn velocity_files() -> Vec<(DateTime<Utc>, VelocityFile)> {
+        vec![
+            velocity_file("2017-06-02T00:00:00Z", 3., -1.),
+            velocity_file("2017-06-01T00:00:00Z", 1., 2.),
+        ]
+    }
+
+    fn profiler() -> Profiler {
+        let polyline = Polyline::new(vec![(0., 5.), (50., 5.)]).unwrap();
+        Profiler::new(polyline).unit(Unit::MetersPerDay)
+    }
+
+    fn distances(profile: &Profile) -> Vec<f64> {
+        profile.samples.iter().map(|s| s.distance).collect()
+    }
+
+    #[test]
+    fn sorted_by_distance_then_time() {
+        let profile = profiler().profile(&velocity_files()).unwrap();
+        // The station at 40 is more than a cell size from every cell center.
+        assert_eq!(
+            vec![0., 0., 10., 10., 20., 20., 30., 30., 50., 50.],
+            distances(&profile)
+        );
+        for pair in profile.samples.chunks(2) {
+            assert!(pair[0].datetime < pair[1].datetime);
+            assert_eq!((1., 2.), (pair[0].along, pair[0].across));
+            assert_eq!((3., -1.), (pair[1].along, pair[1].across));
+        }
+        assert_eq!(Some(10.), profile.samples[0].grid_size);
+        assert_eq!(5., profile.samples[0].offset);
+        assert_eq!(Some(20.), profile.samples[8].grid_size);
+    }
+
+    #[test]
+    fn reversed_line() {
+        let polyline = Polyline::new(vec![(30., 5.), (0., 5.)]).unwrap();
+        let profile = Profiler::new(polyline)
+            .unit(Unit::MetersPerDay)
+            .profile(&velocity_files()[1..])
+            .unwrap();
+        assert_eq!(vec![0., 10., 20., 30.], distances(&profile));
+        for sample in profile.samples.iter() {
+            assert_eq!((-1., -2.), (sample.along, sample.across));
+        }
+    }
+
+    #[test]
+    fn tolerance() {
+        let profile = profiler()
+            .tolerance(4.)
+            .profile(&velocity_files())
+            .unwrap();
+        assert!(profile.samples.is_empty());
+        let profile = profiler()
+            .tolerance(12.)
+            .profile(&velocity_files())
+            .unwrap();
+        assert_eq!(12, profile.samples.len());
+    }
+
+    #[test]
+    fn grid_size() {
+        let profile = profiler()
+            .grid_size(10.)
+            .profile(&velocity_files())
+            .unwrap();
+        assert_eq!(vec![0., 0., 10., 10., 20., 20., 30., 30.], distances(&profile));
+        let profile = profiler()
+            .grid_size(20.)
+            .profile(&velocity_files())
+            .unwrap();
+        assert_eq!(vec![50., 50.], distances(&profile));
+    }
+
+    #[test]
+    fn idw() {
+        let profile = profiler()
+            .sampling(Sampling::Idw { power: 2. })
+            .profile(&velocity_files())
+            .unwrap();
+        assert_eq!(10, profile.samples.len());
+        for pair in profile.samples.chunks(2) {
+            assert_eq!(None, pair[0].grid_size);
+            assert!((pair[0].velocity.x - 1.).abs() < 1e-9);
+            assert!((pair[1].velocity.y + 1.).abs() < 1e-9);
+        }
+    }
+}
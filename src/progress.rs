@@ -0,0 +1,271 @@
+//! Progress reporting for long velocity calculations.
+//!
+//! A `Grid` reports its progress to a `Progress` observer as cells are finished. Two renderers
+//! are provided: `Terminal`, a single status line for people, and `Json`, one JSON object per
+//! line for machines.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Observes the progress of a velocity calculation.
+pub trait Progress: Send + Sync {
+    /// Called whenever the status changes.
+    fn update(&self, status: &Status);
+
+    /// Called once, when every cell has been calculated.
+    fn finish(&self, status: &Status) {
+        self.update(status);
+    }
+}
+
+/// A snapshot of the progress of a velocity calculation.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Status {
+    /// The fraction of finished cells whose registration did not converge.
+    pub convergence_failure_rate: f64,
+
+    /// The number of cells that have been finished, successfully or not.
+    pub done: usize,
+
+    /// The number of seconds since the calculation started.
+    pub elapsed: f64,
+
+    /// The estimated number of seconds until the known cells are finished.
+    ///
+    /// Refinement can add cells as the calculation goes on, so this can grow.
+    pub eta: Option<f64>,
+
+    /// The number of finished cells that failed for any reason.
+    pub failed: usize,
+
+    /// The number of finished cells whose registration did not converge.
+    pub not_converged: usize,
+
+    /// The number of cells that are known but not yet finished.
+    pub remaining: usize,
+
+    /// The number of cells whose results were read from a checkpoint instead of calculated.
+    pub resumed: usize,
+
+    /// The number of cells calculated per second, not counting resumed cells.
+    pub throughput: f64,
+
+    /// The number of cells known so far.
+    pub total: usize,
+}
+
+/// Renders progress as a single, regularly rewritten line on standard error.
+#[derive(Debug)]
+pub struct Terminal {
+    throttle: Throttle,
+}
+
+/// Renders progress as one JSON object per line on standard output.
+#[derive(Debug)]
+pub struct Json {
+    throttle: Throttle,
+}
+
+/// Counts finished cells and passes the status along to a `Progress`.
+pub(crate) struct Tracker {
+    progress: Box<Progress>,
+    start: Instant,
+    status: Mutex<Status>,
+}
+
+#[derive(Debug)]
+struct Throttle {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+/// How a finished cell turned out.
+pub(crate) enum Outcome {
+    Converged,
+    NotConverged,
+    Failed,
+    Resumed,
+}
+
+impl Terminal {
+    /// Creates a new terminal renderer that redraws at most once per interval.
+    pub fn new(interval: Duration) -> Terminal {
+        Terminal { throttle: Throttle::new(interval) }
+    }
+}
+
+impl Progress for Terminal {
+    fn update(&self, status: &Status) {
+        if self.throttle.ready() {
+            let _ = write!(io::stderr(), "\r{}", line(status));
+        }
+    }
+
+    fn finish(&self, status: &Status) {
+        let _ = writeln!(io::stderr(), "\r{}", line(status));
+    }
+}
+
+impl Json {
+    /// Creates a new JSON renderer that writes at most once per interval.
+    pub fn new(interval: Duration) -> Json {
+        Json { throttle: Throttle::new(interval) }
+    }
+
+    fn write(&self, status: &Status) {
+        use serde_json;
+
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        if serde_json::to_writer(&mut stdout, status).is_ok() {
+            let _ = stdout.write_all(b"\n");
+        }
+    }
+}
+
+impl Progress for Json {
+    fn update(&self, status: &Status) {
+        if self.throttle.ready() {
+            self.write(status);
+        }
+    }
+
+    fn finish(&self, status: &Status) {
+        self.write(status);
+    }
+}
+
+impl Tracker {
+    pub(crate) fn new(progress: Box<Progress>) -> Tracker {
+        Tracker {
+            progress: progress,
+            start: Instant::now(),
+            status: Mutex::new(Status::default()),
+        }
+    }
+
+    /// Restarts the clock, for when the calculation actually begins.
+    pub(crate) fn started(mut self) -> Tracker {
+        self.start = Instant::now();
+        self
+    }
+
+    /// Adds newly queued cells to the total.
+    pub(crate) fn queued(&self, cells: usize) {
+        let status = {
+            let mut status = self.status.lock().unwrap();
+            status.total += cells;
+            self.refresh(&mut status);
+            *status
+        };
+        self.progress.update(&status);
+    }
+
+    /// Records a finished cell.
+    pub(crate) fn finished(&self, outcome: Outcome) {
+        let status = {
+            let mut status = self.status.lock().unwrap();
+            status.done += 1;
+            match outcome {
+                Outcome::Converged => {}
+                Outcome::NotConverged => {
+                    status.not_converged += 1;
+                    status.failed += 1;
+                }
+                Outcome::Failed => status.failed += 1,
+                Outcome::Resumed => status.resumed += 1,
+            }
+            self.refresh(&mut status);
+            *status
+        };
+        self.progress.update(&status);
+    }
+
+    /// Reports the final status.
+    pub(crate) fn finish(&self) {
+        let status = {
+            let mut status = self.status.lock().unwrap();
+            self.refresh(&mut status);
+            *status
+        };
+        self.progress.finish(&status);
+    }
+
+    fn refresh(&self, status: &mut Status) {
+        let elapsed = self.start.elapsed();
+        status.elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        status.remaining = status.total - status.done;
+        let calculated = status.done - status.resumed;
+        status.throughput = if status.elapsed > 0. {
+            calculated as f64 / status.elapsed
+        } else {
+            0.
+        };
+        status.eta = if status.throughput > 0. {
+            Some(status.remaining as f64 / status.throughput)
+        } else {
+            None
+        };
+        status.convergence_failure_rate = if calculated > 0 {
+            status.not_converged as f64 / calculated as f64
+        } else {
+            0.
+        };
+    }
+}
+
+impl fmt::Debug for Tracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracker")
+            .field("start", &self.start)
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+impl Throttle {
+    fn new(interval: Duration) -> Throttle {
+        Throttle {
+            interval: interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Returns true if at least one interval has passed since this last returned true.
+    fn ready(&self) -> bool {
+        let mut last = self.last.lock().unwrap();
+        let now = Instant::now();
+        match *last {
+            Some(instant) if now.duration_since(instant) < self.interval => false,
+            _ => {
+                *last = Some(now);
+                true
+            }
+        }
+    }
+}
+
+fn line(status: &Status) -> String {
+    format!(
+        "{}/{} cells, {} remaining, {:.2} cells/s, ETA {}, {:.1}% did not converge",
+        status.done,
+        status.total,
+        status.remaining,
+        status.throughput,
+        status.eta.map(format_seconds).unwrap_or_else(|| "unknown".to_string()),
+        100. * status.convergence_failure_rate
+    )
+}
+
+/// Formats a number of seconds as hours, minutes, and seconds.
+fn format_seconds(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
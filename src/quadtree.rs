@@ -0,0 +1,262 @@
+//! A linear quadtree over the cells of a regular grid.
+//!
+//! Each root is a cell of the base grid. Leaves are stored by address, so cells at different
+//! levels can live side by side, and a leaf can be split into its four children. Roots that
+//! were grown by merging blocks of neighbors live at negative levels.
+
+use geometry::Index;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+
+/// The address of a node in a quadtree.
+///
+/// At level zero the row and column are the base grid index. Each level down halves the cell
+/// size, so a child's row and column are twice its parent's, plus its quadrant.
+///
+/// Grown cells, which cover a square block of base grid cells, are at negative levels: a cell at
+/// level `-n` is `2^n` base grid cells on a side, and its row and column are the base grid index
+/// of its south-west cell. Grown blocks aren't aligned to each other, so they have no parents or
+/// children.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct Address {
+    /// The level of this node. Roots are at level zero, and grown cells are at negative levels.
+    pub level: i32,
+
+    /// The row of this node, in units of this level's cell size.
+    pub row: i64,
+
+    /// The column of this node, in units of this level's cell size.
+    pub col: i64,
+}
+
+/// A quadtree of values, stored by the address of each leaf.
+#[derive(Debug)]
+pub struct Quadtree<T> {
+    leaves: BTreeMap<Address, T>,
+}
+
+impl Address {
+    /// Returns the address of the root at this base grid index.
+    pub fn root(index: Index) -> Address {
+        Address {
+            level: 0,
+            row: index.row,
+            col: index.col,
+        }
+    }
+
+    /// Returns the address of a cell grown to `span` base grid cells on a side, anchored at its
+    /// south-west base grid cell.
+    ///
+    /// The span must be a power of two. A span of one is the root at the anchor.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Index;
+    /// use ape::quadtree::Address;
+    /// let anchor = Index { row: 3, col: -5 };
+    /// assert_eq!(Address::root(anchor), Address::grown(anchor, 1));
+    /// assert_eq!(Address { level: -2, row: 3, col: -5 }, Address::grown(anchor, 4));
+    /// ```
+    pub fn grown(anchor: Index, span: i64) -> Address {
+        assert!(span > 0 && span.count_ones() == 1);
+        Address {
+            level: -(span.trailing_zeros() as i32),
+            row: anchor.row,
+            col: anchor.col,
+        }
+    }
+
+    /// Returns the base grid index of this node's root, or of a grown cell's anchor.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Index;
+    /// use ape::quadtree::Address;
+    /// let address = Address::root(Index { row: -1, col: 2 }).children()[1];
+    /// assert_eq!(Index { row: -1, col: 2 }, address.root_index());
+    /// ```
+    pub fn root_index(&self) -> Index {
+        if self.level < 0 {
+            Index {
+                row: self.row,
+                col: self.col,
+            }
+        } else {
+            Index {
+                row: self.row >> self.level,
+                col: self.col >> self.level,
+            }
+        }
+    }
+
+    /// Returns the indices of every base grid cell this node covers, at least partially.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Index;
+    /// use ape::quadtree::Address;
+    /// let grown = Address::grown(Index { row: 0, col: 1 }, 2).base_indices();
+    /// assert_eq!(4, grown.len());
+    /// assert!(grown.contains(&Index { row: 1, col: 2 }));
+    /// let child = Address::root(Index { row: 0, col: 1 }).children()[0];
+    /// assert_eq!(vec![Index { row: 0, col: 1 }], child.base_indices());
+    /// ```
+    pub fn base_indices(&self) -> Vec<Index> {
+        if self.level >= 0 {
+            return vec![self.root_index()];
+        }
+        let span = 1 << -self.level;
+        let mut indices = Vec::new();
+        for row in self.row..(self.row + span) {
+            for col in self.col..(self.col + span) {
+                indices.push(Index { row: row, col: col });
+            }
+        }
+        indices
+    }
+
+    /// Returns the addresses of this node's four children.
+    ///
+    /// The children are ordered south-west, south-east, north-west, north-east.
+    /// Panics if this is a grown cell.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::geometry::Index;
+    /// use ape::quadtree::Address;
+    /// let children = Address::root(Index { row: 1, col: -1 }).children();
+    /// assert_eq!(Address { level: 1, row: 2, col: -2 }, children[0]);
+    /// assert_eq!(Address { level: 1, row: 3, col: -1 }, children[3]);
+    /// ```
+    pub fn children(&self) -> [Address; 4] {
+        assert!(self.level >= 0, "Grown cells have no children");
+        let child = |quadrant: i64| {
+            Address {
+                level: self.level + 1,
+                row: 2 * self.row + quadrant / 2,
+                col: 2 * self.col + quadrant % 2,
+            }
+        };
+        [child(0), child(1), child(2), child(3)]
+    }
+
+    /// Returns the address of this node's parent, or `None` if this is a root or a grown cell.
+    pub fn parent(&self) -> Option<Address> {
+        if self.level <= 0 {
+            None
+        } else {
+            Some(Address {
+                level: self.level - 1,
+                row: self.row >> 1,
+                col: self.col >> 1,
+            })
+        }
+    }
+}
+
+impl<T> Quadtree<T> {
+    /// Creates a new, empty quadtree.
+    pub fn new() -> Quadtree<T> {
+        Quadtree { leaves: BTreeMap::new() }
+    }
+
+    /// Inserts a leaf, returning the old leaf at that address, if any.
+    pub fn insert(&mut self, address: Address, value: T) -> Option<T> {
+        self.leaves.insert(address, value)
+    }
+
+    /// Returns a reference to a leaf.
+    pub fn get(&self, address: &Address) -> Option<&T> {
+        self.leaves.get(address)
+    }
+
+    /// Returns a mutable reference to a leaf.
+    pub fn get_mut(&mut self, address: &Address) -> Option<&mut T> {
+        self.leaves.get_mut(address)
+    }
+
+    /// Removes a leaf.
+    pub fn remove(&mut self, address: &Address) -> Option<T> {
+        self.leaves.remove(address)
+    }
+
+    /// Returns the addresses of every leaf, in order.
+    pub fn addresses(&self) -> Vec<Address> {
+        self.leaves.keys().cloned().collect()
+    }
+
+    /// Returns an iterator over the leaves.
+    pub fn leaves(&self) -> btree_map::Iter<Address, T> {
+        self.leaves.iter()
+    }
+
+    /// Keeps only the leaves for which the predicate is true.
+    pub fn retain<F: FnMut(&Address, &T) -> bool>(&mut self, mut f: F) {
+        let remove = self.leaves
+            .iter()
+            .filter(|&(address, value)| !f(address, value))
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        for address in remove {
+            self.leaves.remove(&address);
+        }
+    }
+
+    /// Splits a leaf into four children.
+    ///
+    /// The function gets the leaf and returns either its four children, in the order of
+    /// `Address::children`, or the unchanged leaf if it shouldn't be split. Returns true if the
+    /// leaf was split.
+    pub fn split<F>(&mut self, address: &Address, f: F) -> bool
+    where
+        F: FnOnce(T) -> Result<Vec<T>, T>,
+    {
+        let value = match self.leaves.remove(address) {
+            Some(value) => value,
+            None => return false,
+        };
+        match f(value) {
+            Ok(children) => {
+                assert_eq!(4, children.len());
+                for (child, value) in address.children().iter().zip(children) {
+                    self.leaves.insert(*child, value);
+                }
+                true
+            }
+            Err(value) => {
+                self.leaves.insert(*address, value);
+                false
+            }
+        }
+    }
+
+    /// Returns the number of leaves.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Returns true if there are no leaves.
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+}
+
+impl<T> Default for Quadtree<T> {
+    fn default() -> Quadtree<T> {
+        Quadtree::new()
+    }
+}
+
+impl<T> IntoIterator for Quadtree<T> {
+    type Item = (Address, T);
+    type IntoIter = btree_map::IntoIter<Address, T>;
+
+    fn into_iter(self) -> btree_map::IntoIter<Address, T> {
+        self.leaves.into_iter()
+    }
+}
@@ -0,0 +1,503 @@
+//! Registration backends used to calculate cell velocities.
+//!
+//! Every backend registers a moving point cloud onto a fixed one and reports the transform along
+//! with some diagnostics, so backends can be compared on the same grid.
+
+use cpd::{Nonrigid, Rigid};
+use failure::Error;
+use kdtree::KdTree;
+use nalgebra::{Dynamic, Matrix3, Matrix4, Matrix6, MatrixMN, Projective3, U3, Vector3, Vector6};
+use std::f64::consts::PI;
+
+/// A matrix of points, one point per row.
+pub type Points = MatrixMN<f64, Dynamic, U3>;
+
+/// The requested registration method does not exist.
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid registration method: {}", _0)]
+pub struct InvalidMethod(String);
+
+/// Registers a moving point cloud onto a fixed point cloud.
+///
+/// # Examples
+///
+/// Every rigid backend recovers a small translation of a bumpy surface:
+///
+/// ```
+/// use ape::registration::{Icp, Metric, Points, Registration, Translation};
+/// let mut fixed = Points::zeros(100);
+/// let mut moving = Points::zeros(100);
+/// for i in 0..100 {
+///     let (x, y) = ((i % 10) as f64, (i / 10) as f64);
+///     let z = (x / 2.).sin() + (y / 3.).cos();
+///     moving[(i, 0)] = x;
+///     moving[(i, 1)] = y;
+///     moving[(i, 2)] = z;
+///     fixed[(i, 0)] = x + 0.3;
+///     fixed[(i, 1)] = y - 0.2;
+///     fixed[(i, 2)] = z + 0.1;
+/// }
+/// let registrations: Vec<Box<Registration>> = vec![
+///     Box::new(Translation::default()),
+///     Box::new(Icp::default()),
+///     Box::new(Icp { metric: Metric::PointToPlane, ..Default::default() }),
+/// ];
+/// for registration in registrations {
+///     let registered = registration.register(&fixed, &moving).unwrap();
+///     let matrix = registered.transform.matrix();
+///     assert!((matrix[(0, 3)] - 0.3).abs() < 1e-3);
+///     assert!((matrix[(1, 3)] + 0.2).abs() < 1e-3);
+///     assert!((matrix[(2, 3)] - 0.1).abs() < 1e-3);
+/// }
+/// ```
+pub trait Registration: Send + Sync {
+    /// Registers `moving` onto `fixed`.
+    fn register(&self, fixed: &Points, moving: &Points) -> Result<Registered, Error>;
+}
+
+/// The outcome of a registration.
+#[derive(Debug)]
+pub struct Registered {
+    /// Did the registration converge?
+    pub converged: bool,
+
+    /// The number of iterations it took.
+    pub iterations: usize,
+
+    /// The moving points, after registration.
+    pub moved: Points,
+
+    /// The final variance of the gaussian mixture, for probabilistic registrations.
+    pub sigma2: Option<f64>,
+
+    /// The transform that takes the moving points to the fixed points.
+    ///
+    /// Registrations that aren't described by a single transform, e.g. nonrigid cpd, report the
+    /// mean displacement as a translation.
+    pub transform: Projective3<f64>,
+}
+
+/// Quality metrics for a registration.
+#[derive(Clone, Copy, Debug)]
+pub struct Quality {
+    /// The fraction of moved points that have a fixed point within the overlap tolerance.
+    pub overlap: f64,
+
+    /// The root mean square distance from each moved point to its nearest fixed point.
+    pub rms: f64,
+}
+
+/// Translation-only coherent point drift.
+///
+/// This is rigid cpd without the rotation, which is more stable for small, flat cells where the
+/// rotation is poorly constrained.
+#[derive(Clone, Copy, Debug)]
+pub struct Translation {
+    /// The maximum number of iterations.
+    pub max_iterations: usize,
+
+    /// Iteration stops when the change in sigma2 falls below this tolerance.
+    pub tolerance: f64,
+
+    /// The weight of the uniform outlier distribution, between zero and one.
+    pub w: f64,
+}
+
+/// Iterative closest point.
+#[derive(Clone, Copy, Debug)]
+pub struct Icp {
+    /// Correspondences further apart than this are ignored.
+    pub max_distance: Option<f64>,
+
+    /// The maximum number of iterations.
+    pub max_iterations: usize,
+
+    /// The error metric that is minimized.
+    pub metric: Metric,
+
+    /// Iteration stops when the change in the mean squared error falls below this tolerance.
+    pub tolerance: f64,
+}
+
+/// The error metric used by iterative closest point.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    /// Minimize the distance between corresponding points.
+    PointToPoint,
+
+    /// Minimize the distance from each moving point to the plane of its fixed correspondence.
+    PointToPlane,
+}
+
+/// Returns a registration by name, as used by the `--method` command line flag.
+///
+/// The rigid and nonrigid methods use the provided, pre-configured, cpd registrations.
+pub fn from_name(
+    name: &str,
+    rigid: Rigid,
+    nonrigid: Nonrigid,
+) -> Result<Box<Registration>, Error> {
+    match name {
+        "cpd" => Ok(Box::new(rigid)),
+        "translation" => Ok(Box::new(Translation::default())),
+        "icp" => Ok(Box::new(Icp::default())),
+        "icp-plane" => Ok(Box::new(Icp {
+            metric: Metric::PointToPlane,
+            ..Default::default()
+        })),
+        "nonrigid" => Ok(Box::new(nonrigid)),
+        _ => Err(InvalidMethod(name.to_string()).into()),
+    }
+}
+
+impl<R: Registration + ?Sized> Registration for Box<R> {
+    fn register(&self, fixed: &Points, moving: &Points) -> Result<Registered, Error> {
+        (**self).register(fixed, moving)
+    }
+}
+
+impl Registration for Rigid {
+    fn register(&self, fixed: &Points, moving: &Points) -> Result<Registered, Error> {
+        let run = Rigid::register(self, fixed, moving)?;
+        let transform = Projective3::from_matrix_unchecked(*run.transform.as_transform3().matrix());
+        Ok(Registered {
+            converged: run.converged,
+            iterations: run.iterations,
+            moved: run.moved,
+            sigma2: Some(run.sigma2),
+            transform: transform,
+        })
+    }
+}
+
+impl Registration for Nonrigid {
+    fn register(&self, fixed: &Points, moving: &Points) -> Result<Registered, Error> {
+        let run = Nonrigid::register(self, fixed, moving)?;
+        let displacement = mean(&(&run.moved - moving));
+        Ok(Registered {
+            converged: run.converged,
+            iterations: run.iterations,
+            moved: run.moved,
+            sigma2: Some(run.sigma2),
+            transform: from_parts(&Matrix3::identity(), &displacement),
+        })
+    }
+}
+
+impl Registered {
+    /// Returns the rotation angle of the transform, in degrees.
+    ///
+    /// This is the angle of the axis-angle representation of the transform's rotation.
+    pub fn rotation(&self) -> f64 {
+        let matrix = self.transform.matrix();
+        let trace = matrix[(0, 0)] + matrix[(1, 1)] + matrix[(2, 2)];
+        ((trace - 1.) / 2.).max(-1.).min(1.).acos().to_degrees()
+    }
+}
+
+impl Quality {
+    /// Calculates the quality of a registration from the fixed points and the moved points.
+    ///
+    /// Moved points with a fixed point within `tolerance` are counted as overlapping.
+    pub fn new(fixed: &Points, moved: &Points, tolerance: f64) -> Quality {
+        let tree = KdTree::new(to_arrays(fixed), 3);
+        let mut sum = 0.;
+        let mut overlapping = 0;
+        for i in 0..moved.nrows() {
+            let point = row(moved, i);
+            if let Some((_, distance2)) = tree.nearest(&[point.x, point.y, point.z]) {
+                sum += distance2;
+                if distance2 <= tolerance * tolerance {
+                    overlapping += 1;
+                }
+            }
+        }
+        let n = moved.nrows() as f64;
+        Quality {
+            overlap: overlapping as f64 / n,
+            rms: (sum / n).sqrt(),
+        }
+    }
+}
+
+impl Default for Translation {
+    fn default() -> Translation {
+        Translation {
+            max_iterations: 150,
+            tolerance: 1e-5,
+            w: 0.,
+        }
+    }
+}
+
+impl Registration for Translation {
+    fn register(&self, fixed: &Points, moving: &Points) -> Result<Registered, Error> {
+        let n = fixed.nrows();
+        let m = moving.nrows();
+        let mut translation = Vector3::zeros();
+        let mut sigma2 = initial_sigma2(fixed, moving);
+        let mut kernel = vec![0.; m];
+        let mut iterations = 0;
+        let mut converged = false;
+        while iterations < self.max_iterations && !converged && sigma2 > 0. {
+            let constant = (2. * PI * sigma2).powf(1.5) * self.w / (1. - self.w) * m as f64 /
+                n as f64;
+            let mut np = 0.;
+            let mut weighted_fixed = Vector3::zeros();
+            let mut weighted_moving = Vector3::zeros();
+            let mut weighted_distance2 = 0.;
+            for i in 0..n {
+                let x = row(fixed, i);
+                let mut denominator = constant;
+                for j in 0..m {
+                    let distance2 = (x - row(moving, j) - translation).norm_squared();
+                    kernel[j] = (-distance2 / (2. * sigma2)).exp();
+                    denominator += kernel[j];
+                }
+                if denominator <= 0. {
+                    continue;
+                }
+                for j in 0..m {
+                    let p = kernel[j] / denominator;
+                    let y = row(moving, j);
+                    np += p;
+                    weighted_fixed += x * p;
+                    weighted_moving += y * p;
+                    weighted_distance2 += p * (x - y).norm_squared();
+                }
+            }
+            if np == 0. {
+                break;
+            }
+            translation = (weighted_fixed - weighted_moving) / np;
+            let previous = sigma2;
+            sigma2 = (weighted_distance2 - np * translation.norm_squared()) / (3. * np);
+            iterations += 1;
+            converged = (previous - sigma2).abs() < self.tolerance;
+        }
+        Ok(Registered {
+            converged: converged,
+            iterations: iterations,
+            moved: translate(moving, &translation),
+            sigma2: Some(sigma2),
+            transform: from_parts(&Matrix3::identity(), &translation),
+        })
+    }
+}
+
+impl Default for Icp {
+    fn default() -> Icp {
+        Icp {
+            max_distance: None,
+            max_iterations: 50,
+            metric: Metric::PointToPoint,
+            tolerance: 1e-6,
+        }
+    }
+}
+
+impl Registration for Icp {
+    fn register(&self, fixed: &Points, moving: &Points) -> Result<Registered, Error> {
+        let tree = KdTree::new(to_arrays(fixed), 3);
+        let normals = if self.metric == Metric::PointToPlane {
+            Some(estimate_normals(&tree, fixed))
+        } else {
+            None
+        };
+        let max_distance2 = self.max_distance.map(|d| d * d);
+        let mut rotation = Matrix3::identity();
+        let mut translation = Vector3::zeros();
+        let mut moved = moving.clone();
+        let mut previous_error = None;
+        let mut iterations = 0;
+        let mut converged = false;
+        while iterations < self.max_iterations && !converged {
+            let mut pairs = Vec::with_capacity(moved.nrows());
+            let mut error = 0.;
+            for i in 0..moved.nrows() {
+                let point = row(&moved, i);
+                if let Some((j, distance2)) = tree.nearest(&[point.x, point.y, point.z]) {
+                    if max_distance2.map(|m| distance2 <= m).unwrap_or(true) {
+                        pairs.push((point, row(fixed, j), j));
+                        error += distance2;
+                    }
+                }
+            }
+            if pairs.len() < 3 {
+                break;
+            }
+            error /= pairs.len() as f64;
+            let step = match normals {
+                Some(ref normals) => point_to_plane_step(&pairs, normals),
+                None => point_to_point_step(&pairs),
+            };
+            let (step_rotation, step_translation) = match step {
+                Some(step) => step,
+                None => break,
+            };
+            rotation = step_rotation * rotation;
+            translation = step_rotation * translation + step_translation;
+            moved = transform_points(moving, &rotation, &translation);
+            iterations += 1;
+            converged = previous_error
+                .map(|previous: f64| (previous - error).abs() < self.tolerance)
+                .unwrap_or(false);
+            previous_error = Some(error);
+        }
+        Ok(Registered {
+            converged: converged,
+            iterations: iterations,
+            moved: moved,
+            sigma2: None,
+            transform: from_parts(&rotation, &translation),
+        })
+    }
+}
+
+fn point_to_point_step(
+    pairs: &[(Vector3<f64>, Vector3<f64>, usize)],
+) -> Option<(Matrix3<f64>, Vector3<f64>)> {
+    let n = pairs.len() as f64;
+    let moving_mean = pairs.iter().fold(Vector3::zeros(), |acc, p| acc + p.0) / n;
+    let fixed_mean = pairs.iter().fold(Vector3::zeros(), |acc, p| acc + p.1) / n;
+    let mut covariance = Matrix3::zeros();
+    for &(moving, fixed, _) in pairs {
+        covariance += (fixed - fixed_mean) * (moving - moving_mean).transpose();
+    }
+    let svd = covariance.svd(true, true);
+    let u = svd.u?;
+    let v_t = svd.v_t?;
+    let mut d = Matrix3::identity();
+    if (u * v_t).determinant() < 0. {
+        d[(2, 2)] = -1.;
+    }
+    let rotation = u * d * v_t;
+    Some((rotation, fixed_mean - rotation * moving_mean))
+}
+
+fn point_to_plane_step(
+    pairs: &[(Vector3<f64>, Vector3<f64>, usize)],
+    normals: &[Vector3<f64>],
+) -> Option<(Matrix3<f64>, Vector3<f64>)> {
+    let mut a = Matrix6::zeros();
+    let mut b = Vector6::zeros();
+    for &(moving, fixed, j) in pairs {
+        let normal = normals[j];
+        let cross = moving.cross(&normal);
+        let row = Vector6::new(cross.x, cross.y, cross.z, normal.x, normal.y, normal.z);
+        let residual = (fixed - moving).dot(&normal);
+        a += row * row.transpose();
+        b += row * residual;
+    }
+    let x = a.try_inverse()? * b;
+    Some((
+        rotation_from_angles(x[0], x[1], x[2]),
+        Vector3::new(x[3], x[4], x[5]),
+    ))
+}
+
+/// Estimates a unit normal for each point from the plane through its nearest neighbors.
+fn estimate_normals(tree: &KdTree, points: &Points) -> Vec<Vector3<f64>> {
+    (0..points.nrows())
+        .map(|i| {
+            let point = row(points, i);
+            let neighbors = tree.nearest_k(&[point.x, point.y, point.z], 10)
+                .into_iter()
+                .map(|(j, _)| row(points, j))
+                .collect::<Vec<_>>();
+            let centroid = neighbors.iter().fold(Vector3::zeros(), |acc, p| acc + p) /
+                neighbors.len() as f64;
+            let mut covariance = Matrix3::zeros();
+            for neighbor in neighbors.iter() {
+                let d = neighbor - centroid;
+                covariance += d * d.transpose();
+            }
+            let eigen = covariance.symmetric_eigen();
+            let mut smallest = 0;
+            for k in 1..3 {
+                if eigen.eigenvalues[k] < eigen.eigenvalues[smallest] {
+                    smallest = k;
+                }
+            }
+            let normal = eigen.eigenvectors.column(smallest).into_owned();
+            let norm = normal.norm();
+            if norm > 0. {
+                normal / norm
+            } else {
+                Vector3::z()
+            }
+        })
+        .collect()
+}
+
+fn rotation_from_angles(alpha: f64, beta: f64, gamma: f64) -> Matrix3<f64> {
+    let (sa, ca) = alpha.sin_cos();
+    let (sb, cb) = beta.sin_cos();
+    let (sg, cg) = gamma.sin_cos();
+    Matrix3::new(
+        cg * cb,
+        -sg * ca + cg * sb * sa,
+        sg * sa + cg * sb * ca,
+        sg * cb,
+        cg * ca + sg * sb * sa,
+        -cg * sa + sg * sb * ca,
+        -sb,
+        cb * sa,
+        cb * ca,
+    )
+}
+
+fn initial_sigma2(fixed: &Points, moving: &Points) -> f64 {
+    let mut sum = 0.;
+    for i in 0..fixed.nrows() {
+        let x = row(fixed, i);
+        for j in 0..moving.nrows() {
+            sum += (x - row(moving, j)).norm_squared();
+        }
+    }
+    sum / (3 * fixed.nrows() * moving.nrows()) as f64
+}
+
+fn row(points: &Points, i: usize) -> Vector3<f64> {
+    Vector3::new(points[(i, 0)], points[(i, 1)], points[(i, 2)])
+}
+
+fn mean(points: &Points) -> Vector3<f64> {
+    let mut sum = Vector3::zeros();
+    for i in 0..points.nrows() {
+        sum += row(points, i);
+    }
+    sum / points.nrows() as f64
+}
+
+fn to_arrays(points: &Points) -> Vec<[f64; 3]> {
+    (0..points.nrows())
+        .map(|i| [points[(i, 0)], points[(i, 1)], points[(i, 2)]])
+        .collect()
+}
+
+fn translate(points: &Points, translation: &Vector3<f64>) -> Points {
+    transform_points(points, &Matrix3::identity(), translation)
+}
+
+fn transform_points(points: &Points, rotation: &Matrix3<f64>, translation: &Vector3<f64>) -> Points {
+    let mut transformed = points.clone();
+    for i in 0..points.nrows() {
+        let point = rotation * row(points, i) + translation;
+        for d in 0..3 {
+            transformed[(i, d)] = point[d];
+        }
+    }
+    transformed
+}
+
+fn from_parts(rotation: &Matrix3<f64>, translation: &Vector3<f64>) -> Projective3<f64> {
+    let mut matrix = Matrix4::identity();
+    for i in 0..3 {
+        for j in 0..3 {
+            matrix[(i, j)] = rotation[(i, j)];
+        }
+        matrix[(i, 3)] = translation[i];
+    }
+    Projective3::from_matrix_unchecked(matrix)
+}
@@ -0,0 +1,117 @@
+//! A work-stealing scheduler that hands out the most expensive work first.
+//!
+//! Work is dealt out to one queue per worker, most expensive first, always to the queue with the
+//! least total cost. Each worker takes from the front of its own queue, so it works on its most
+//! expensive items first. A worker whose queue is empty steals from the front of the busiest
+//! queue, so no worker sits idle while there is work left.
+
+use std::collections::VecDeque;
+use std::sync::{Mutex, MutexGuard};
+
+/// Hands out work to a fixed number of workers.
+#[derive(Debug)]
+pub struct Scheduler<T> {
+    queues: Vec<Mutex<VecDeque<T>>>,
+}
+
+impl<T> Scheduler<T> {
+    /// Creates a new scheduler, estimating the cost of each item with a function.
+    pub fn new<F: Fn(&T) -> f64>(mut items: Vec<T>, workers: usize, cost: F) -> Scheduler<T> {
+        use std::cmp::Ordering;
+
+        assert!(workers > 0);
+        items.sort_by(|a, b| {
+            cost(b).partial_cmp(&cost(a)).unwrap_or(Ordering::Equal)
+        });
+        let mut queues = (0..workers).map(|_| VecDeque::new()).collect::<Vec<_>>();
+        let mut loads = vec![0.; workers];
+        for item in items {
+            let worker = (0..workers)
+                .min_by(|&a, &b| {
+                    loads[a].partial_cmp(&loads[b]).unwrap_or(Ordering::Equal)
+                })
+                .unwrap();
+            loads[worker] += cost(&item);
+            queues[worker].push_back(item);
+        }
+        Scheduler { queues: queues.into_iter().map(Mutex::new).collect() }
+    }
+
+    /// Returns the next item for a worker, stealing from another worker if its own queue is
+    /// empty, along with the approximate number of items left.
+    ///
+    /// Returns `None` once there is no work left anywhere.
+    pub fn next(&self, worker: usize) -> Option<(T, usize)> {
+        let item = self.lock(worker).pop_front();
+        let item = item.or_else(|| self.steal(worker));
+        item.map(|item| (item, self.remaining()))
+    }
+
+    /// Returns the approximate number of items left in all queues.
+    pub fn remaining(&self) -> usize {
+        (0..self.queues.len()).map(|i| self.lock(i).len()).sum()
+    }
+
+    fn steal(&self, thief: usize) -> Option<T> {
+        let mut victims = (0..self.queues.len())
+            .filter(|&i| i != thief)
+            .map(|i| (self.lock(i).len(), i))
+            .collect::<Vec<_>>();
+        victims.sort_by(|a, b| b.cmp(a));
+        victims
+            .into_iter()
+            .filter_map(|(_, i)| self.lock(i).pop_front())
+            .next()
+    }
+
+    fn lock(&self, worker: usize) -> MutexGuard<VecDeque<T>> {
+        self.queues[worker].lock().unwrap_or_else(
+            |err| err.into_inner(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn every_item_once() {
+        let scheduler = Arc::new(Scheduler::new(
+            (0..1000).collect::<Vec<usize>>(),
+            4,
+            |&i| (i % 17) as f64,
+        ));
+        let handles = (0..4)
+            .map(|worker| {
+                let scheduler = scheduler.clone();
+                thread::spawn(move || {
+                    let mut items = Vec::new();
+                    while let Some((item, _)) = scheduler.next(worker) {
+                        items.push(item);
+                    }
+                    items
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut items = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+        items.sort();
+        assert_eq!((0..1000).collect::<Vec<_>>(), items);
+        assert_eq!(0, scheduler.remaining());
+    }
+
+    #[test]
+    fn empty_queue_steals() {
+        let scheduler = Scheduler::new(vec![10., 1., 1.], 2, |&cost| cost);
+        assert_eq!(Some((10., 2)), scheduler.next(0));
+        assert_eq!(Some((1., 1)), scheduler.next(0));
+        assert_eq!(Some((1., 0)), scheduler.next(1));
+        assert_eq!(None, scheduler.next(0));
+        assert_eq!(None, scheduler.next(1));
+    }
+}
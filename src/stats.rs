@@ -0,0 +1,401 @@
+//! Robust statistics for velocity time series.
+//!
+//! The median and the median absolute deviation are much less sensitive to a few bad velocities
+//! than the mean and standard deviation, so both are reported. Trends are ordinary least squares
+//! fits against time, with a confidence interval on the slope from Student's t distribution.
+
+use std::cmp::Ordering;
+use std::f64;
+
+/// The factor that scales the median absolute deviation to the standard deviation of normally
+/// distributed values.
+pub const MAD_SCALE: f64 = 1.4826;
+
+/// Summarizes series of values.
+#[derive(Clone, Debug)]
+pub struct Statistics {
+    confidence: f64,
+    percentiles: Vec<f64>,
+    trim: f64,
+}
+
+/// A summary of a series of values.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Summary {
+    /// The number of values.
+    pub count: usize,
+
+    /// The median absolute deviation from the median, unscaled.
+    pub mad: f64,
+
+    /// The arithmetic mean.
+    pub mean: f64,
+
+    /// The median.
+    pub median: f64,
+
+    /// The requested percentiles, as (percentile, value).
+    pub percentiles: Vec<(f64, f64)>,
+
+    /// The sample standard deviation, if there are at least two values.
+    pub std: Option<f64>,
+
+    /// The linear trend against time, if there are at least three values at two or more times.
+    pub trend: Option<Trend>,
+
+    /// The mean of the values left after trimming both tails.
+    pub trimmed_mean: f64,
+}
+
+/// A linear trend, `intercept + slope * time`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Trend {
+    /// The value at time zero.
+    pub intercept: f64,
+
+    /// The change in value per unit of time.
+    pub slope: f64,
+
+    /// The upper end of the slope's confidence interval.
+    pub slope_high: f64,
+
+    /// The lower end of the slope's confidence interval.
+    pub slope_low: f64,
+}
+
+impl Statistics {
+    /// Creates a new summarizer.
+    ///
+    /// By default the 5th, 25th, 75th, and 95th percentiles are reported, the trimmed mean drops
+    /// 10% of the values from each tail, and the trend has a 95% confidence interval.
+    pub fn new() -> Statistics {
+        Statistics {
+            confidence: 0.95,
+            percentiles: vec![5., 25., 75., 95.],
+            trim: 0.1,
+        }
+    }
+
+    /// Sets the percentiles to report, each between 0 and 100.
+    pub fn percentiles(mut self, percentiles: Vec<f64>) -> Statistics {
+        self.percentiles = percentiles;
+        self
+    }
+
+    /// Sets the fraction of values dropped from each tail for the trimmed mean.
+    pub fn trim(mut self, trim: f64) -> Statistics {
+        self.trim = trim;
+        self
+    }
+
+    /// Sets the confidence level of the trend's confidence interval, e.g. 0.95.
+    pub fn confidence(mut self, confidence: f64) -> Statistics {
+        self.confidence = confidence;
+        self
+    }
+
+    /// Returns the requested percentiles.
+    pub fn requested_percentiles(&self) -> &[f64] {
+        &self.percentiles
+    }
+
+    /// Summarizes values observed at some times, or returns `None` if there are no values.
+    pub fn summarize(&self, times: &[f64], values: &[f64]) -> Option<Summary> {
+        Some(Summary {
+            count: values.len(),
+            mad: mad(values)?,
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            median: median(values)?,
+            percentiles: self.percentiles
+                .iter()
+                .map(|&p| percentile(values, p).map(|value| (p, value)))
+                .collect::<Option<Vec<_>>>()?,
+            std: std(values),
+            trend: trend(times, values, self.confidence),
+            trimmed_mean: trimmed_mean(values, self.trim)?,
+        })
+    }
+}
+
+impl Default for Statistics {
+    fn default() -> Statistics {
+        Statistics::new()
+    }
+}
+
+impl Summary {
+    /// Returns the robust z-score of a value, its distance from the median in scaled median
+    /// absolute deviations, or `None` if the deviation is zero.
+    pub fn robust_z(&self, value: f64) -> Option<f64> {
+        let scale = MAD_SCALE * self.mad;
+        if scale > 0. {
+            Some((value - self.median) / scale)
+        } else {
+            None
+        }
+    }
+}
+
+impl Trend {
+    /// Returns the trend's value at a time.
+    pub fn at(&self, time: f64) -> f64 {
+        self.intercept + self.slope * time
+    }
+}
+
+/// Returns the median of some values, or `None` if there are none.
+///
+/// # Examples
+///
+/// ```
+/// use ape::stats::median;
+/// assert_eq!(Some(2.5), median(&[4., 1., 2., 3.]));
+/// assert_eq!(None, median(&[]));
+/// ```
+pub fn median(values: &[f64]) -> Option<f64> {
+    percentile(values, 50.)
+}
+
+/// Returns the median absolute deviation from the median, unscaled.
+///
+/// # Examples
+///
+/// ```
+/// use ape::stats::mad;
+/// assert_eq!(Some(1.), mad(&[1., 2., 3., 4., 100.]));
+/// ```
+pub fn mad(values: &[f64]) -> Option<f64> {
+    let center = median(values)?;
+    median(&values
+        .iter()
+        .map(|value| (value - center).abs())
+        .collect::<Vec<_>>())
+}
+
+/// Returns the sample standard deviation, or `None` if there are fewer than two values.
+pub fn std(values: &[f64]) -> Option<f64> {
+    if values.len() < 2 {
+        return None;
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    Some(
+        (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.)).sqrt(),
+    )
+}
+
+/// Returns a percentile, between 0 and 100, linearly interpolated between the closest ranks.
+///
+/// # Examples
+///
+/// ```
+/// use ape::stats::percentile;
+/// let values = [1., 2., 3., 4., 5.];
+/// assert_eq!(Some(1.), percentile(&values, 0.));
+/// assert_eq!(Some(2.), percentile(&values, 25.));
+/// assert_eq!(Some(4.6), percentile(&values, 90.));
+/// ```
+pub fn percentile(values: &[f64], percentile: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let values = sorted(values);
+    let rank = (percentile / 100.).max(0.).min(1.) * (values.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    Some(values[lower] + (rank - lower as f64) * (values[upper] - values[lower]))
+}
+
+/// Returns the mean after dropping a fraction of the values from each tail.
+///
+/// # Examples
+///
+/// ```
+/// use ape::stats::trimmed_mean;
+/// let values = [1., 2., 3., 4., 100.];
+/// assert_eq!(Some(3.), trimmed_mean(&values, 0.2));
+/// ```
+pub fn trimmed_mean(values: &[f64], fraction: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let values = sorted(values);
+    let trim = ((values.len() as f64 * fraction.max(0.)).floor() as usize).min(
+        (values.len() - 1) / 2,
+    );
+    let kept = &values[trim..values.len() - trim];
+    Some(kept.iter().sum::<f64>() / kept.len() as f64)
+}
+
+/// Fits a linear trend of values against times, with a confidence interval on the slope.
+///
+/// Returns `None` if there are fewer than three values or all of the times are the same.
+///
+/// # Examples
+///
+/// ```
+/// use ape::stats::trend;
+/// let trend = trend(&[0., 1., 2., 3.], &[1., 3., 5., 7.], 0.95).unwrap();
+/// assert!((trend.slope - 2.).abs() < 1e-12);
+/// assert!((trend.intercept - 1.).abs() < 1e-12);
+/// assert!((trend.slope_high - trend.slope_low).abs() < 1e-9);
+/// ```
+///
+/// A noisy fit has a slope of 1.9 with a standard error of 0.1732, so its 95% confidence
+/// interval is 1.9 +/- 3.182 * 0.1732:
+///
+/// ```
+/// use ape::stats::trend;
+/// let trend = trend(&[0., 1., 2., 3., 4.], &[1., 3.5, 4.5, 7.5, 8.5], 0.95).unwrap();
+/// assert!((trend.slope - 1.9).abs() < 1e-12);
+/// assert!((trend.intercept - 1.2).abs() < 1e-12);
+/// assert!((trend.slope_low - 1.3488).abs() < 1e-4);
+/// assert!((trend.slope_high - 2.4512).abs() < 1e-4);
+/// ```
+pub fn trend(times: &[f64], values: &[f64], confidence: f64) -> Option<Trend> {
+    let n = values.len();
+    if n < 3 || times.len() != n {
+        return None;
+    }
+    let mean_t = times.iter().sum::<f64>() / n as f64;
+    let mean_v = values.iter().sum::<f64>() / n as f64;
+    let stt = times.iter().map(|t| (t - mean_t).powi(2)).sum::<f64>();
+    if stt == 0. {
+        return None;
+    }
+    let stv = times
+        .iter()
+        .zip(values)
+        .map(|(t, v)| (t - mean_t) * (v - mean_v))
+        .sum::<f64>();
+    let slope = stv / stt;
+    let intercept = mean_v - slope * mean_t;
+    let residuals = times
+        .iter()
+        .zip(values)
+        .map(|(t, v)| (v - intercept - slope * t).powi(2))
+        .sum::<f64>();
+    let dof = (n - 2) as f64;
+    let standard_error = (residuals / dof / stt).sqrt();
+    let half_width = t_quantile(0.5 + confidence / 2., dof) * standard_error;
+    Some(Trend {
+        intercept: intercept,
+        slope: slope,
+        slope_high: slope + half_width,
+        slope_low: slope - half_width,
+    })
+}
+
+fn sorted(values: &[f64]) -> Vec<f64> {
+    let mut values = values.to_vec();
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    values
+}
+
+/// Returns the quantile of Student's t distribution with some degrees of freedom, for a
+/// probability of at least one half, found by bisection of its distribution function.
+fn t_quantile(p: f64, dof: f64) -> f64 {
+    let (mut low, mut high) = (0., 1.);
+    while t_cdf(high, dof) < p {
+        high *= 2.;
+        if high > 1e12 {
+            return f64::INFINITY;
+        }
+    }
+    for _ in 0..200 {
+        let mid = (low + high) / 2.;
+        if t_cdf(mid, dof) < p {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    (low + high) / 2.
+}
+
+/// Student's t distribution function, for `t >= 0`.
+fn t_cdf(t: f64, dof: f64) -> f64 {
+    1. - 0.5 * incomplete_beta(dof / (dof + t * t), dof / 2., 0.5)
+}
+
+/// The regularized incomplete beta function, from its continued fraction.
+fn incomplete_beta(x: f64, a: f64, b: f64) -> f64 {
+    if x <= 0. {
+        return 0.;
+    } else if x >= 1. {
+        return 1.;
+    }
+    let front = (ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1. - x).ln())
+        .exp();
+    if x < (a + 1.) / (a + b + 2.) {
+        front * beta_fraction(x, a, b) / a
+    } else {
+        1. - front * beta_fraction(1. - x, b, a) / b
+    }
+}
+
+/// Lentz's method for the incomplete beta continued fraction.
+fn beta_fraction(x: f64, a: f64, b: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let mut c = 1.;
+    let mut d = 1. - (a + b) * x / (a + 1.);
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1. / d;
+    let mut h = d;
+    for m in 1..300 {
+        let m = m as f64;
+        let numerator = m * (b - m) * x / ((a + 2. * m - 1.) * (a + 2. * m));
+        d = 1. + numerator * d;
+        d = if d.abs() < TINY { 1. / TINY } else { 1. / d };
+        c = 1. + numerator / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        h *= d * c;
+        let numerator = -(a + m) * (a + b + m) * x / ((a + 2. * m) * (a + 2. * m + 1.));
+        d = 1. + numerator * d;
+        d = if d.abs() < TINY { 1. / TINY } else { 1. / d };
+        c = 1. + numerator / c;
+        if c.abs() < TINY {
+            c = TINY;
+        }
+        let delta = d * c;
+        h *= delta;
+        if (delta - 1.).abs() < 1e-15 {
+            break;
+        }
+    }
+    h
+}
+
+/// The natural log of the gamma function, with the Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let series = COEFFICIENTS.iter().enumerate().fold(
+        1.000000000190015,
+        |sum, (i, c)| sum + c / (x + 1. + i as f64),
+    );
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_quantiles() {
+        assert!((t_quantile(0.975, 2.) - 4.303).abs() < 1e-3);
+        assert!((t_quantile(0.975, 10.) - 2.228).abs() < 1e-3);
+        assert!((t_quantile(0.95, 30.) - 1.697).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,215 @@
+//! Strain rates and other kinematic fields derived from a regular velocity field.
+//!
+//! Horizontal velocity gradients are estimated with finite differences between the nodes of an
+//! interpolated `Field`. Central differences are used where both neighbors have velocities, and
+//! one-sided differences where only one does.
+
+use failure::Error;
+use interpolate::Field;
+use std::io::Write;
+use std::path::Path;
+use velocities::Unit;
+
+/// The strain rates at a point, derived from the horizontal velocity gradient.
+///
+/// Rates are per unit of time of the velocity unit, e.g. per year for meters per year. The
+/// horizontal components are the x (east) and y (north) directions.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Strain {
+    /// The divergence of the horizontal velocity, `exx + eyy`.
+    pub divergence: f64,
+
+    /// The effective strain rate, the second invariant of the strain rate tensor with the
+    /// vertical strain rate from incompressibility.
+    pub effective: f64,
+
+    /// The longitudinal strain rate in x.
+    pub exx: f64,
+
+    /// The shear strain rate.
+    pub exy: f64,
+
+    /// The longitudinal strain rate in y.
+    pub eyy: f64,
+
+    /// The horizontal velocity gradient tensor, `[[dvx/dx, dvx/dy], [dvy/dx, dvy/dy]]`.
+    pub gradient: [[f64; 2]; 2],
+
+    /// The principal strain rates, most extensional first.
+    pub principal: [f64; 2],
+
+    /// The direction of the most extensional principal axis, in degrees counterclockwise from
+    /// east, between -90 and 90.
+    pub principal_direction: f64,
+
+    /// The vertical vorticity, `dvy/dx - dvx/dy`.
+    pub vorticity: f64,
+}
+
+/// A regular grid of strain rates.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StrainField {
+    /// The number of columns.
+    pub cols: usize,
+
+    /// The nodes, row by row from south to north, each row from west to east.
+    pub nodes: Vec<StrainNode>,
+
+    /// The easting of the south-west node.
+    pub origin_x: f64,
+
+    /// The northing of the south-west node.
+    pub origin_y: f64,
+
+    /// The number of rows.
+    pub rows: usize,
+
+    /// The distance between adjacent nodes.
+    pub spacing: f64,
+
+    /// The unit of the velocities the strain rates were derived from.
+    pub unit: Unit,
+}
+
+/// A node in a strain field.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct StrainNode {
+    /// The strain rates, or `None` if there were too few velocities around the node.
+    pub strain: Option<Strain>,
+
+    /// The easting of the node.
+    pub x: f64,
+
+    /// The northing of the node.
+    pub y: f64,
+}
+
+impl Strain {
+    /// Derives the strain rates from a horizontal velocity gradient tensor.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::strain::Strain;
+    /// // Pure shear, extending in x and compressing in y.
+    /// let strain = Strain::from_gradient([[0.1, 0.], [0., -0.1]]);
+    /// assert_eq!([0.1, -0.1], strain.principal);
+    /// assert_eq!(0., strain.principal_direction);
+    /// assert_eq!(0., strain.divergence);
+    /// assert!((strain.effective - 0.1).abs() < 1e-12);
+    /// ```
+    pub fn from_gradient(gradient: [[f64; 2]; 2]) -> Strain {
+        let exx = gradient[0][0];
+        let eyy = gradient[1][1];
+        let exy = (gradient[0][1] + gradient[1][0]) / 2.;
+        let mean = (exx + eyy) / 2.;
+        let radius = (((exx - eyy) / 2.).powi(2) + exy.powi(2)).sqrt();
+        Strain {
+            divergence: exx + eyy,
+            effective: (exx.powi(2) + eyy.powi(2) + exx * eyy + exy.powi(2)).sqrt(),
+            exx: exx,
+            exy: exy,
+            eyy: eyy,
+            gradient: gradient,
+            principal: [mean + radius, mean - radius],
+            principal_direction: (2. * exy).atan2(exx - eyy).to_degrees() / 2.,
+            vorticity: gradient[1][0] - gradient[0][1],
+        }
+    }
+}
+
+impl StrainField {
+    /// Calculates the strain rates at every node of a velocity field.
+    pub fn new(field: &Field) -> StrainField {
+        let mut nodes = Vec::with_capacity(field.nodes.len());
+        for row in 0..field.rows {
+            for col in 0..field.cols {
+                let node = field.node(row, col).unwrap();
+                let dx = derivative(field, row, col, 0, 1);
+                let dy = derivative(field, row, col, 1, 0);
+                let strain = match (dx, dy) {
+                    (Some(dx), Some(dy)) => Some(Strain::from_gradient(
+                        [[dx[0], dy[0]], [dx[1], dy[1]]],
+                    )),
+                    _ => None,
+                };
+                nodes.push(StrainNode {
+                    strain: strain,
+                    x: node.x,
+                    y: node.y,
+                });
+            }
+        }
+        StrainField {
+            cols: field.cols,
+            nodes: nodes,
+            origin_x: field.origin_x,
+            origin_y: field.origin_y,
+            rows: field.rows,
+            spacing: field.spacing,
+            unit: field.unit,
+        }
+    }
+
+    /// Writes this strain field to a JSON file.
+    pub fn to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        super::write_json(self, path)
+    }
+
+    /// Writes this strain field as csv, one node per line.
+    pub fn to_csv<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        writeln!(
+            writer,
+            "x,y,dvx_dx,dvx_dy,dvy_dx,dvy_dy,exx,eyy,exy,e1,e2,e1_direction,effective,\
+             divergence,vorticity"
+        )?;
+        for node in self.nodes.iter() {
+            match node.strain {
+                Some(strain) => {
+                    writeln!(
+                        writer,
+                        "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                        node.x,
+                        node.y,
+                        strain.gradient[0][0],
+                        strain.gradient[0][1],
+                        strain.gradient[1][0],
+                        strain.gradient[1][1],
+                        strain.exx,
+                        strain.eyy,
+                        strain.exy,
+                        strain.principal[0],
+                        strain.principal[1],
+                        strain.principal_direction,
+                        strain.effective,
+                        strain.divergence,
+                        strain.vorticity
+                    )?
+                }
+                None => writeln!(writer, "{},{},,,,,,,,,,,,,", node.x, node.y)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the derivative of the horizontal velocity along rows (`dr`) or columns (`dc`).
+fn derivative(field: &Field, row: usize, col: usize, dr: i64, dc: i64) -> Option<[f64; 2]> {
+    let velocity = |offset: i64| {
+        let r = row as i64 + offset * dr;
+        let c = col as i64 + offset * dc;
+        if r < 0 || c < 0 {
+            None
+        } else {
+            field.node(r as usize, c as usize).and_then(|node| node.velocity)
+        }
+    };
+    let (a, b, steps) = match (velocity(-1), velocity(0), velocity(1)) {
+        (Some(a), _, Some(b)) => (a, b, 2.),
+        (None, Some(a), Some(b)) |
+        (Some(a), Some(b), None) => (a, b, 1.),
+        _ => return None,
+    };
+    let distance = steps * field.spacing;
+    Some([(b.x - a.x) / distance, (b.y - a.y) / distance])
+}
@@ -0,0 +1,320 @@
+//! A persistent store of velocity time series, one series per grid cell.
+//!
+//! Velocity files are ingested once, with each file's time taken from its name. The store can
+//! then be queried by cell, bounding box, and time range, and exported as tidy csv with one row
+//! per observation.
+
+use chrono::{DateTime, Utc};
+use failure::Error;
+use geometry::Bounds;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use string_from_option;
+use velocities::{Velocity, VelocityFile};
+
+/// The current version of the time series store format.
+pub const VERSION: u32 = 1;
+
+/// The cell key could not be parsed.
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid cell key, expected x,y,size: {}", _0)]
+pub struct InvalidKey(String);
+
+/// Identifies a cell across velocity files.
+///
+/// The key is the cell's lower-left corner and size, rounded to the millimeter, so the same cell
+/// from different runs gets the same key even if its coordinates differ by rounding error.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct Key {
+    /// The easting of the lower-left corner, in millimeters.
+    pub x: i64,
+
+    /// The northing of the lower-left corner, in millimeters.
+    pub y: i64,
+
+    /// The size of the cell, in millimeters.
+    pub size: i64,
+}
+
+/// A velocity observation in a time series.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Observation {
+    /// The date and time of the observation, from the velocity file name.
+    pub datetime: DateTime<Utc>,
+
+    /// The name of the velocity file the observation came from.
+    pub source: String,
+
+    /// The velocity.
+    pub velocity: Velocity,
+}
+
+/// The time series of one cell.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Series {
+    /// The cell.
+    pub key: Key,
+
+    /// The observations, in time order.
+    pub observations: Vec<Observation>,
+}
+
+/// A store of time series.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Store {
+    /// The version of the store format.
+    pub version: u32,
+
+    /// The time series, sorted by key.
+    pub series: Vec<Series>,
+
+    /// The names of the velocity files that have been ingested.
+    pub sources: BTreeSet<String>,
+}
+
+/// A query of a time series store.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Query {
+    bounds: Option<Bounds>,
+    end: Option<DateTime<Utc>>,
+    key: Option<Key>,
+    start: Option<DateTime<Utc>>,
+}
+
+impl Key {
+    /// Creates a key from a cell's lower-left corner and size.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::timeseries::Key;
+    /// let key = Key::new(100.0004, -200., 50.);
+    /// assert_eq!(Key { x: 100000, y: -200000, size: 50000 }, key);
+    /// ```
+    pub fn new(x: f64, y: f64, size: f64) -> Key {
+        Key {
+            x: (x * 1000.).round() as i64,
+            y: (y * 1000.).round() as i64,
+            size: (size * 1000.).round() as i64,
+        }
+    }
+
+    /// Returns the key of a velocity's cell.
+    pub fn from_velocity(velocity: &Velocity) -> Key {
+        Key::new(velocity.x, velocity.y, velocity.grid_size)
+    }
+
+    /// Returns the bounds of this key's cell.
+    pub fn bounds(&self) -> Bounds {
+        Bounds::new(
+            self.x as f64 / 1000.,
+            self.y as f64 / 1000.,
+            self.size as f64 / 1000.,
+        )
+    }
+}
+
+impl Store {
+    /// Creates a new, empty store.
+    pub fn new() -> Store {
+        Store {
+            version: VERSION,
+            series: Vec::new(),
+            sources: BTreeSet::new(),
+        }
+    }
+
+    /// Reads a store from a path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Store, Error> {
+        super::read_json(path)
+    }
+
+    /// Writes this store to a path.
+    pub fn to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        super::write_json(self, path)
+    }
+
+    /// Ingests a velocity file, taking the observation time from its file name.
+    ///
+    /// Returns false, and does nothing, if a file with the same name was already ingested.
+    pub fn ingest<P: AsRef<Path>>(&mut self, path: P) -> Result<bool, Error> {
+        let source = path.as_ref()
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.as_ref().display().to_string());
+        if self.sources.contains(&source) {
+            return Ok(false);
+        }
+        let datetime = super::datetime_from_path(&path)?;
+        let velocity_file = VelocityFile::from_path(&path)?;
+        for velocity in velocity_file.velocities {
+            self.insert(Observation {
+                datetime: datetime,
+                source: source.clone(),
+                velocity: velocity,
+            });
+        }
+        self.sources.insert(source);
+        Ok(true)
+    }
+
+    /// Inserts an observation into its cell's series, keeping the series in time order.
+    pub fn insert(&mut self, observation: Observation) {
+        let key = Key::from_velocity(&observation.velocity);
+        let index = match self.series.binary_search_by_key(&key, |series| series.key) {
+            Ok(index) => index,
+            Err(index) => {
+                self.series.insert(
+                    index,
+                    Series {
+                        key: key,
+                        observations: Vec::new(),
+                    },
+                );
+                index
+            }
+        };
+        let observations = &mut self.series[index].observations;
+        let position = observations
+            .iter()
+            .position(|o| o.datetime > observation.datetime)
+            .unwrap_or_else(|| observations.len());
+        observations.insert(position, observation);
+    }
+
+    /// Returns the series of a cell.
+    pub fn get(&self, key: &Key) -> Option<&Series> {
+        self.series
+            .binary_search_by_key(key, |series| series.key)
+            .ok()
+            .map(|index| &self.series[index])
+    }
+
+    /// Returns every observation that matches a query, cell by cell and then in time order.
+    pub fn query(&self, query: &Query) -> Vec<(Key, &Observation)> {
+        self.series
+            .iter()
+            .filter(|series| query.matches_key(&series.key))
+            .flat_map(|series| {
+                series
+                    .observations
+                    .iter()
+                    .filter(|o| query.matches_datetime(&o.datetime))
+                    .map(move |o| (series.key, o))
+            })
+            .collect()
+    }
+
+    /// Writes the observations that match a query as tidy csv, one observation per row.
+    pub fn to_csv<W: Write>(&self, query: &Query, mut writer: W) -> Result<(), Error> {
+        writeln!(
+            writer,
+            "datetime,x,y,grid_size,vx,vy,vz,vxy,v,unit,separation,rms,flags,source"
+        )?;
+        for (key, observation) in self.query(query) {
+            let velocity = &observation.velocity;
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                observation.datetime.to_rfc3339(),
+                key.x as f64 / 1000.,
+                key.y as f64 / 1000.,
+                key.size as f64 / 1000.,
+                velocity.velocity.x,
+                velocity.velocity.y,
+                velocity.velocity.z,
+                velocity.velocity.xy(),
+                velocity.velocity.magnitude(),
+                velocity.unit,
+                string_from_option(velocity.separation),
+                string_from_option(velocity.rms),
+                velocity
+                    .flags
+                    .iter()
+                    .map(|flag| flag.to_string())
+                    .collect::<Vec<_>>()
+                    .join("|"),
+                observation.source
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Query {
+    /// Creates a query that matches everything.
+    pub fn new() -> Query {
+        Query::default()
+    }
+
+    /// Only matches one cell.
+    pub fn key(mut self, key: Key) -> Query {
+        self.key = Some(key);
+        self
+    }
+
+    /// Only matches cells whose centers are within some bounds.
+    pub fn bounds(mut self, bounds: Bounds) -> Query {
+        self.bounds = Some(bounds);
+        self
+    }
+
+    /// Only matches observations at or after a time.
+    pub fn start(mut self, start: DateTime<Utc>) -> Query {
+        self.start = Some(start);
+        self
+    }
+
+    /// Only matches observations at or before a time.
+    pub fn end(mut self, end: DateTime<Utc>) -> Query {
+        self.end = Some(end);
+        self
+    }
+
+    fn matches_key(&self, key: &Key) -> bool {
+        self.key.map(|k| k == *key).unwrap_or(true) &&
+            self.bounds
+                .map(|bounds| {
+                    let (x, y) = key.bounds().center();
+                    x >= bounds.min_x && x <= bounds.max_x && y >= bounds.min_y &&
+                        y <= bounds.max_y
+                })
+                .unwrap_or(true)
+    }
+
+    fn matches_datetime(&self, datetime: &DateTime<Utc>) -> bool {
+        self.start.map(|start| *datetime >= start).unwrap_or(true) &&
+            self.end.map(|end| *datetime <= end).unwrap_or(true)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{}",
+            self.x as f64 / 1000.,
+            self.y as f64 / 1000.,
+            self.size as f64 / 1000.
+        )
+    }
+}
+
+impl ::std::str::FromStr for Key {
+    type Err = Error;
+
+    /// Parses a key from `x,y,size`, in meters.
+    fn from_str(s: &str) -> Result<Key, Error> {
+        let numbers = s.split(',')
+            .map(|n| n.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| InvalidKey(s.to_string()))?;
+        if numbers.len() == 3 {
+            Ok(Key::new(numbers[0], numbers[1], numbers[2]))
+        } else {
+            Err(InvalidKey(s.to_string()).into())
+        }
+    }
+}
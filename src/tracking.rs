@@ -0,0 +1,538 @@
+//! Lagrangian particle tracking through a sequence of velocity files.
+//!
+//! Particles are advected with fourth-order Runge-Kutta steps. The velocity at a point and time is
+//! interpolated in space with inverse distance weighting within each velocity file, and linearly
+//! in time between the files on either side, which are ordered by the dates and times in their
+//! names. Before the first file and after the last the nearest file is used.
+//!
+//! A particle stops when it reaches the target line, if there is one, or when it moves too far
+//! from any velocity to interpolate.
+
+use Vector;
+use {duration, seconds, string_from_option};
+use chrono::{DateTime, Duration, Utc};
+use failure::Error;
+use geometry::{Bounds, Polyline};
+use interpolate::Samples;
+use std::io::Write;
+use std::path::Path;
+use velocities::VelocityFile;
+
+/// The current version of the trajectories file format.
+pub const VERSION: u32 = 1;
+
+/// The time step must be positive.
+#[derive(Debug, Fail)]
+#[fail(display = "The time step must be positive, not {}", _0)]
+pub struct InvalidStep(f64);
+
+/// There were no velocity files to track through.
+#[derive(Debug, Fail)]
+#[fail(display = "No velocity files to track through")]
+pub struct NoVelocityFiles {}
+
+/// Advects particles through velocity files.
+#[derive(Clone, Debug)]
+pub struct Tracer {
+    end: Option<DateTime<Utc>>,
+    include_flagged: bool,
+    max_distance: Option<f64>,
+    neighbors: usize,
+    power: f64,
+    start: Option<DateTime<Utc>>,
+    step: f64,
+    target: Option<Polyline>,
+}
+
+/// The trajectories of tracked particles.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Trajectories {
+    /// The version of the file format.
+    pub version: u32,
+
+    /// The time tracking stopped.
+    pub end: DateTime<Utc>,
+
+    /// The particles.
+    pub particles: Vec<Particle>,
+
+    /// The time the particles were seeded.
+    pub start: DateTime<Utc>,
+
+    /// The time step, in seconds.
+    pub step: f64,
+}
+
+/// A tracked particle.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Particle {
+    /// The time the particle reached the target line, if it did.
+    pub arrival: Option<DateTime<Utc>>,
+
+    /// Why the particle stopped.
+    pub fate: Fate,
+
+    /// The index of the particle's seed.
+    pub id: usize,
+
+    /// The particle's positions, starting with its seed.
+    pub points: Vec<TrackPoint>,
+}
+
+/// A position of a particle.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct TrackPoint {
+    /// The time the particle was here.
+    pub datetime: DateTime<Utc>,
+
+    /// The easting.
+    pub x: f64,
+
+    /// The northing.
+    pub y: f64,
+}
+
+/// Why a particle stopped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Fate {
+    /// The particle reached the target line.
+    Arrived,
+
+    /// The particle was tracked until the end time.
+    Completed,
+
+    /// The particle moved too far from any velocity.
+    Exited,
+}
+
+/// The velocities of one file, in meters per second.
+#[derive(Debug)]
+struct Frame {
+    datetime: DateTime<Utc>,
+    max_distance: f64,
+    samples: Samples,
+}
+
+impl Tracer {
+    /// Creates a new tracer.
+    ///
+    /// By default particles are tracked from the first velocity file's time to the last with
+    /// one hour steps, using the twelve nearest velocities with an inverse distance power of two.
+    pub fn new() -> Tracer {
+        Tracer {
+            end: None,
+            include_flagged: false,
+            max_distance: None,
+            neighbors: 12,
+            power: 2.,
+            start: None,
+            step: 3600.,
+            target: None,
+        }
+    }
+
+    /// Sets the time step, in seconds.
+    ///
+    /// Steps are rounded to the millisecond, and tracking fails if the step isn't positive.
+    pub fn step(mut self, step: f64) -> Tracer {
+        self.step = step;
+        self
+    }
+
+    /// Sets the time the particles are seeded, instead of the first velocity file's time.
+    pub fn start<T: Into<Option<DateTime<Utc>>>>(mut self, start: T) -> Tracer {
+        self.start = start.into();
+        self
+    }
+
+    /// Sets the time tracking stops, instead of the last velocity file's time.
+    pub fn end<T: Into<Option<DateTime<Utc>>>>(mut self, end: T) -> Tracer {
+        self.end = end.into();
+        self
+    }
+
+    /// Sets the farthest a particle can be from a velocity and still be moved.
+    ///
+    /// Defaults to each velocity file's cell size.
+    pub fn max_distance<T: Into<Option<f64>>>(mut self, max_distance: T) -> Tracer {
+        self.max_distance = max_distance.into();
+        self
+    }
+
+    /// Sets the number of nearest velocities used to interpolate.
+    pub fn neighbors(mut self, neighbors: usize) -> Tracer {
+        self.neighbors = neighbors;
+        self
+    }
+
+    /// Sets the inverse distance weighting power.
+    pub fn power(mut self, power: f64) -> Tracer {
+        self.power = power;
+        self
+    }
+
+    /// Sets the line whose crossing stops a particle and records its arrival time.
+    pub fn target<T: Into<Option<Polyline>>>(mut self, target: T) -> Tracer {
+        self.target = target.into();
+        self
+    }
+
+    /// Includes velocities flagged as outliers in the velocity fields.
+    pub fn include_flagged(mut self, include_flagged: bool) -> Tracer {
+        self.include_flagged = include_flagged;
+        self
+    }
+
+    /// Tracks particles from seed positions through velocity files and their dates and times.
+    pub fn track(
+        &self,
+        velocity_files: &[(DateTime<Utc>, VelocityFile)],
+        seeds: &[(f64, f64)],
+    ) -> Result<Trajectories, Error> {
+        if !(self.step > 0.) {
+            return Err(InvalidStep(self.step).into());
+        }
+        let mut frames = velocity_files
+            .iter()
+            .map(|&(datetime, ref velocity_file)| self.frame(datetime, velocity_file))
+            .collect::<Vec<_>>();
+        if frames.is_empty() {
+            return Err(NoVelocityFiles {}.into());
+        }
+        frames.sort_by_key(|frame| frame.datetime);
+        let start = self.start.unwrap_or(frames[0].datetime);
+        let end = self.end.unwrap_or(frames[frames.len() - 1].datetime);
+        let particles = seeds
+            .iter()
+            .enumerate()
+            .map(|(id, &seed)| self.advect(&frames, id, seed, start, end))
+            .collect();
+        Ok(Trajectories {
+            version: VERSION,
+            end: end,
+            particles: particles,
+            start: start,
+            step: self.step,
+        })
+    }
+
+    fn frame(&self, datetime: DateTime<Utc>, velocity_file: &VelocityFile) -> Frame {
+        let velocities = velocity_file.included(self.include_flagged);
+        Frame {
+            datetime: datetime,
+            max_distance: self.max_distance.unwrap_or(velocity_file.geometry.cell_size),
+            samples: Samples::new(
+                velocities
+                    .iter()
+                    .map(|v| [v.center_of_gravity.x, v.center_of_gravity.y, 0.])
+                    .collect(),
+                velocities
+                    .iter()
+                    .map(|v| v.velocity / v.unit.seconds())
+                    .collect(),
+            ),
+        }
+    }
+
+    fn advect(
+        &self,
+        frames: &[Frame],
+        id: usize,
+        seed: (f64, f64),
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Particle {
+        let mut points = vec![
+            TrackPoint {
+                datetime: start,
+                x: seed.0,
+                y: seed.1,
+            },
+        ];
+        let mut datetime = start;
+        let mut position = seed;
+        let step = duration(self.step).max(Duration::milliseconds(1));
+        loop {
+            let remaining = end.signed_duration_since(datetime);
+            if remaining <= Duration::zero() {
+                return particle(id, None, Fate::Completed, points);
+            }
+            let step = step.min(remaining);
+            let dt = seconds(step);
+            let next = match self.rk4(frames, datetime, position, dt) {
+                Some(next) => next,
+                None => return particle(id, None, Fate::Exited, points),
+            };
+            if let Some(fraction) = self.target.as_ref().and_then(
+                |target| target.crossing(position, next),
+            )
+            {
+                let arrival = datetime + duration(fraction * dt);
+                points.push(TrackPoint {
+                    datetime: arrival,
+                    x: position.0 + fraction * (next.0 - position.0),
+                    y: position.1 + fraction * (next.1 - position.1),
+                });
+                return particle(id, Some(arrival), Fate::Arrived, points);
+            }
+            datetime = datetime + step;
+            position = next;
+            points.push(TrackPoint {
+                datetime: datetime,
+                x: position.0,
+                y: position.1,
+            });
+        }
+    }
+
+    /// Takes one fourth-order Runge-Kutta step.
+    fn rk4(
+        &self,
+        frames: &[Frame],
+        datetime: DateTime<Utc>,
+        (x, y): (f64, f64),
+        dt: f64,
+    ) -> Option<(f64, f64)> {
+        let half = datetime + duration(dt / 2.);
+        let k1 = self.velocity(frames, datetime, x, y)?;
+        let k2 = self.velocity(frames, half, x + k1.x * dt / 2., y + k1.y * dt / 2.)?;
+        let k3 = self.velocity(frames, half, x + k2.x * dt / 2., y + k2.y * dt / 2.)?;
+        let k4 = self.velocity(
+            frames,
+            datetime + duration(dt),
+            x + k3.x * dt,
+            y + k3.y * dt,
+        )?;
+        let velocity = (k1 + k2 * 2. + k3 * 2. + k4) / 6.;
+        Some((x + velocity.x * dt, y + velocity.y * dt))
+    }
+
+    /// Returns the velocity at a point and time, interpolated in time between frames.
+    fn velocity(
+        &self,
+        frames: &[Frame],
+        datetime: DateTime<Utc>,
+        x: f64,
+        y: f64,
+    ) -> Option<Vector> {
+        let after = frames
+            .iter()
+            .position(|frame| frame.datetime > datetime)
+            .unwrap_or_else(|| frames.len());
+        if after == 0 {
+            self.frame_velocity(&frames[0], x, y)
+        } else if after == frames.len() {
+            self.frame_velocity(&frames[after - 1], x, y)
+        } else {
+            let (a, b) = (&frames[after - 1], &frames[after]);
+            let weight = seconds(datetime.signed_duration_since(a.datetime)) /
+                seconds(b.datetime.signed_duration_since(a.datetime));
+            let va = self.frame_velocity(a, x, y)?;
+            let vb = self.frame_velocity(b, x, y)?;
+            Some(va * (1. - weight) + vb * weight)
+        }
+    }
+
+    fn frame_velocity(&self, frame: &Frame, x: f64, y: f64) -> Option<Vector> {
+        if frame.samples.distance(x, y) > frame.max_distance {
+            None
+        } else {
+            Some(frame.samples.idw(x, y, self.power, self.neighbors).0)
+        }
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Tracer {
+        Tracer::new()
+    }
+}
+
+impl Trajectories {
+    /// Writes these trajectories to a path.
+    pub fn to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        super::write_json(self, path)
+    }
+
+    /// Writes the particle positions as csv, one position per line.
+    pub fn to_csv<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        writeln!(writer, "id,datetime,x,y")?;
+        for particle in self.particles.iter() {
+            for point in particle.points.iter() {
+                writeln!(
+                    writer,
+                    "{},{},{},{}",
+                    particle.id,
+                    point.datetime.to_rfc3339(),
+                    point.x,
+                    point.y
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes each particle's seed, fate, and arrival time as csv, one particle per line.
+    pub fn arrivals_to_csv<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        writeln!(writer, "id,x,y,fate,arrival,hours")?;
+        for particle in self.particles.iter() {
+            let seed = particle.points[0];
+            writeln!(
+                writer,
+                "{},{},{},{},{},{}",
+                particle.id,
+                seed.x,
+                seed.y,
+                match particle.fate {
+                    Fate::Arrived => "arrived",
+                    Fate::Completed => "completed",
+                    Fate::Exited => "exited",
+                },
+                string_from_option(particle.arrival.map(|a| a.to_rfc3339())),
+                string_from_option(particle.arrival.map(|a| {
+                    seconds(a.signed_duration_since(self.start)) / 3600.
+                }))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns seeds at the centers of the squares of a regular grid covering some bounds.
+///
+/// # Examples
+///
+/// ```
+/// use ape::geometry::Bounds;
+/// use ape::tracking::seed_grid;
+/// let seeds = seed_grid(&Bounds::new(0., 0., 10.), 5.);
+/// assert_eq!(vec![(2.5, 2.5), (7.5, 2.5), (2.5, 7.5), (7.5, 7.5)], seeds);
+/// ```
+pub fn seed_grid(bounds: &Bounds, spacing: f64) -> Vec<(f64, f64)> {
+    let cols = ((bounds.max_x - bounds.min_x) / spacing).ceil().max(1.) as usize;
+    let rows = ((bounds.max_y - bounds.min_y) / spacing).ceil().max(1.) as usize;
+    let mut seeds = Vec::with_capacity(rows * cols);
+    for row in 0..rows {
+        for col in 0..cols {
+            seeds.push((
+                bounds.min_x + (col as f64 + 0.5) * spacing,
+                bounds.min_y + (row as f64 + 0.5) * spacing,
+            ));
+        }
+    }
+    seeds
+}
+
+fn particle(
+    id: usize,
+    arrival: Option<DateTime<Utc>>,
+    fate: Fate,
+    points: Vec<TrackPoint>,
+) -> Particle {
+    Particle {
+        arrival: arrival,
+        fate: fate,
+        id: id,
+        points: points,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geometry::Geometry;
+    use serde_json;
+    use velocities::Velocity;
+
+    fn start() -> DateTime<Utc> {
+        "2017-06-01T00:00:00Z".parse().unwrap()
+    }
+
+    /// Returns a velocity file with the same velocity, in meters per hour, in every cell.
+    fn uniform(vx: f64, vy: f64) -> VelocityFile {
+        let mut velocities = Vec::new();
+        for row in 0..10 {
+            for col in 0..10 {
+                let (x, y) = (col as f64 * 10., row as f64 * 10.);
+                velocities.push(
+                    serde_json::from_value::<Velocity>(json!({
+                        "after_points": 300,
+                        "before_points": 300,
+                        "center_of_gravity": {"x": x + 5., "y": y + 5., "z": 0.},
+                        "col": col,
+                        "datetime": "2017-06-01T00:00:00Z",
+                        "grid_size": 10.,
+                        "iterations": 10,
+                        "row": row,
+                        "unit": "m/h",
+                        "velocity": {"x": vx, "y": vy, "z": 0.},
+                        "x": x,
+                        "y": y
+                    })).unwrap(),
+                );
+            }
+        }
+        VelocityFile::new(Geometry::new(10.).unwrap(), velocities)
+    }
+
+    #[test]
+    fn uniform_field() {
+        let trajectories = Tracer::new()
+            .end(start() + Duration::hours(10))
+            .track(&[(start(), uniform(1., 0.5))], &[(50., 50.)])
+            .unwrap();
+        let particle = &trajectories.particles[0];
+        assert_eq!(Fate::Completed, particle.fate);
+        assert_eq!(11, particle.points.len());
+        let last = particle.points[10];
+        assert_eq!(start() + Duration::hours(10), last.datetime);
+        assert!((last.x - 60.).abs() < 1e-9);
+        assert!((last.y - 55.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn time_interpolation() {
+        let trajectories = Tracer::new()
+            .track(
+                &[
+                    (start(), uniform(1., 0.)),
+                    (start() + Duration::hours(10), uniform(3., 0.)),
+                ],
+                &[(20., 50.)],
+            )
+            .unwrap();
+        let points = &trajectories.particles[0].points;
+        assert_eq!(11, points.len());
+        assert!((points[5].x - 27.5).abs() < 1e-9);
+        assert!((points[10].x - 40.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn arrival() {
+        let target = Polyline::new(vec![(52.5, 0.), (52.5, 100.)]).unwrap();
+        let trajectories = Tracer::new()
+            .end(start() + Duration::hours(10))
+            .target(target)
+            .track(&[(start(), uniform(1., 0.))], &[(50., 50.)])
+            .unwrap();
+        let particle = &trajectories.particles[0];
+        assert_eq!(Fate::Arrived, particle.fate);
+        assert_eq!(
+            Some(start() + Duration::minutes(150)),
+            particle.arrival
+        );
+        let last = particle.points[particle.points.len() - 1];
+        assert!((last.x - 52.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn invalid_step() {
+        assert!(
+            Tracer::new()
+                .step(0.)
+                .track(&[(start(), uniform(1., 0.))], &[(50., 50.)])
+                .is_err()
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use std::iter::FromIterator;
-use std::ops::Div;
+use std::ops::{Add, Div, Mul, Sub};
 
 /// A three-dimensional vector.
 #[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
@@ -20,6 +20,24 @@ impl Vector {
         })
     }
 
+    /// Returns the sample covariance of these vectors, or `None` if there are fewer than two.
+    pub fn covariance(vectors: &[Vector]) -> Option<[[f64; 3]; 3]> {
+        if vectors.len() < 2 {
+            return None;
+        }
+        let mean = Vector::mean(&vectors.to_vec());
+        let mut covariance = [[0.; 3]; 3];
+        for vector in vectors {
+            let d = [vector.x - mean.x, vector.y - mean.y, vector.z - mean.z];
+            for i in 0..3 {
+                for j in 0..3 {
+                    covariance[i][j] += d[i] * d[j] / (vectors.len() - 1) as f64;
+                }
+            }
+        }
+        Some(covariance)
+    }
+
     pub fn xy(&self) -> f64 {
         (self.x * self.x + self.y * self.y).sqrt()
     }
@@ -29,6 +47,18 @@ impl Vector {
     }
 }
 
+impl Add for Vector {
+    type Output = Vector;
+
+    fn add(self, other: Vector) -> Vector {
+        Vector {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
 impl Div<f64> for Vector {
     type Output = Vector;
 
@@ -41,6 +71,30 @@ impl Div<f64> for Vector {
     }
 }
 
+impl Mul<f64> for Vector {
+    type Output = Vector;
+
+    fn mul(self, other: f64) -> Vector {
+        Vector {
+            x: self.x * other,
+            y: self.y * other,
+            z: self.z * other,
+        }
+    }
+}
+
+impl Sub for Vector {
+    type Output = Vector;
+
+    fn sub(self, other: Vector) -> Vector {
+        Vector {
+            x: self.x - other.x,
+            y: self.y - other.y,
+            z: self.z - other.z,
+        }
+    }
+}
+
 impl FromIterator<f64> for Vector {
     fn from_iter<T>(iter: T) -> Vector
     where
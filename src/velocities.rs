@@ -1,48 +1,180 @@
 use Vector;
+use checkpoint::{Checkpoint, Settings};
 use chrono::{DateTime, Duration, Utc};
-use cpd::Rigid;
+use clean::Flag;
 use failure::Error;
+use geometry::{Bounds, Geometry, Index};
+use kdtree::KdTree;
 use las::Point;
-use std::collections::HashMap;
+use num_cpus;
+use progress::{Outcome, Progress, Tracker};
+use quadtree::{Address, Quadtree};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use registration::{Points, Quality, Registration};
+use scheduler::Scheduler;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// The current version of the velocity file format.
+///
+/// Version zero is the original format, which was a bare list of velocities with the `x` and `y`
+/// fields swapped. Version one did not record cell bounds.
+pub const VERSION: u32 = 2;
 
 /// The velocity calculation did not converge.
 #[derive(Debug, Fail)]
-#[fail(display = "Did not converge")]
-pub struct DidNotConverge {}
+#[fail(display = "Did not converge after {} iterations", iterations)]
+pub struct DidNotConverge {
+    /// The number of iterations that were run.
+    pub iterations: usize,
+
+    /// The root mean square distance between the registered points and the after points.
+    pub rms: f64,
+
+    /// The final variance of the gaussian mixture, for probabilistic registrations.
+    pub sigma2: Option<f64>,
+}
+
+/// The before and after scans were taken at the same time, so no velocity can be calculated.
+#[derive(Debug, Fail)]
+#[fail(display = "The before and after scans have the same datetime")]
+pub struct ZeroSeparation {}
+
+/// The requested velocity unit does not exist.
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid velocity unit: {}", _0)]
+pub struct InvalidUnit(String);
+
+/// A cell whose velocity could not be calculated.
+#[derive(Clone, Debug, Fail, Serialize, Deserialize)]
+#[fail(display = "Cell ({}, {}) at level {}: {}: {}", row, col, level, kind, message)]
+pub struct Failure {
+    /// The number of points in the cell's after cloud.
+    pub after_points: usize,
+
+    /// The number of points in the cell's before cloud.
+    pub before_points: usize,
+
+    /// The bounds of the cell.
+    pub bounds: Bounds,
+
+    /// The column of the cell in the grid, in units of the cell's level or, for grown cells, the
+    /// base grid column of its south-west cell.
+    pub col: i64,
+
+    /// The size of the cell.
+    pub grid_size: f64,
+
+    /// What went wrong.
+    pub kind: FailureKind,
+
+    /// The number of iterations the registration ran, if it did not converge.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iterations: Option<usize>,
+
+    /// The quadtree level of the cell. Grown cells are at negative levels.
+    pub level: i32,
+
+    /// A description of the failure.
+    pub message: String,
+
+    /// The root mean square distance between the registered points and the after points, if the
+    /// registration did not converge.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rms: Option<f64>,
+
+    /// The row of the cell in the grid, in units of the cell's level or, for grown cells, the
+    /// base grid row of its south-west cell.
+    pub row: i64,
 
-/// Calculate velocities over a large area using rigid cpd.
+    /// The final variance of the gaussian mixture, if a probabilistic registration did not
+    /// converge.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sigma2: Option<f64>,
+}
+
+/// The reason a cell's velocity could not be calculated.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    /// The registration did not converge.
+    NotConverged,
+
+    /// The registration returned an error.
+    Registration,
+
+    /// The cell had too few points, even after growing.
+    TooFewPoints,
+
+    /// The worker thread panicked while calculating the cell's velocity.
+    Panic,
+}
+
+/// The unit of a velocity.
+///
+/// Files written before units were recorded are in meters per hour.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Unit {
+    /// Meters per hour.
+    #[serde(rename = "m/h")]
+    MetersPerHour,
+
+    /// Meters per day.
+    #[serde(rename = "m/day")]
+    MetersPerDay,
+
+    /// Meters per (Julian) year.
+    #[serde(rename = "m/yr")]
+    MetersPerYear,
+}
+
+/// Calculate velocities over a large area by registering gridded point clouds.
 #[derive(Debug)]
 pub struct Builder {
     after: Vec<Point>,
     before: Vec<Point>,
-    datetime: DateTime<Utc>,
-    duration: Duration,
-    grid_size: i64,
+    grow_healthy: bool,
     min_points: usize,
     ngrow: usize,
+    parameters: Parameters,
+    prior: Option<Prior>,
+    refinement: Option<(f64, f64)>,
 }
 
 /// A grid of cells, used to calculate velocities.
 #[derive(Debug)]
 pub struct Grid {
-    data: HashMap<(i64, i64), Cell>,
-    datetime: DateTime<Utc>,
-    duration: Duration,
+    checkpoint: Option<Arc<Checkpoint>>,
+    clouds: Arc<Clouds>,
+    failures: Vec<Failure>,
+    parameters: Parameters,
+    refinement: Option<Refinement>,
+    settings: Settings,
+    tracker: Option<Tracker>,
+    tree: Quadtree<Cell>,
 }
 
 /// A cell in a grid.
+///
+/// Cells hold indices into their grid's point clouds, so overlapping cells share points.
 #[derive(Debug)]
 pub struct Cell {
-    after: Vec<Point>,
-    before: Vec<Point>,
-    coordinates: (i64, i64),
-    grid_size: i64,
+    address: Address,
+    after: Vec<usize>,
+    before: Vec<usize>,
+    bounds: Bounds,
+    grid_size: f64,
+    members: Vec<Index>,
+    prior: Option<Vector>,
 }
 
 /// A velocity measurement.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Velocity {
     /// The number of points in the after matrix.
     pub after_points: usize,
@@ -50,28 +182,174 @@ pub struct Velocity {
     /// The number of points in the before matrix.
     pub before_points: usize,
 
+    /// The number of bootstrap resamples that registered and converged, and so were used to
+    /// estimate the velocity's uncertainty.
+    #[serde(default)]
+    pub bootstrap_samples: Option<usize>,
+
+    /// The bounds of the cell used for this velocity.
+    #[serde(default)]
+    pub bounds: Bounds,
+
     /// The center of gravity of the point.
     pub center_of_gravity: Vector,
 
+    /// The displacement between the centers of gravity of the before and after points.
+    #[serde(default)]
+    pub centroid_displacement: Option<Vector>,
+
+    /// The column of the cell in the grid, in units of the cell's level or, for grown cells, the
+    /// base grid column of its south-west cell.
+    #[serde(default)]
+    pub col: i64,
+
     /// The date and time of this velocity measurement.
     pub datetime: DateTime<Utc>,
 
+    /// The mean displacement of the before points, as calculated by the registration.
+    #[serde(default)]
+    pub displacement: Option<Vector>,
+
+    /// The reasons this velocity was flagged as a spatial outlier, if it was.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flags: Vec<Flag>,
+
     /// The size of the grid of the cell used for this velocity.
-    pub grid_size: i64,
+    pub grid_size: f64,
 
     /// The number of iterations it took.
     pub iterations: usize,
 
-    /// The mean displacement between the points, divided by the number of hours in between scans.
+    /// The quadtree level of the cell. Base grid cells are level zero, and each refinement adds
+    /// one and halves the cell size. Grown cells are at negative levels, `-n` for a cell `2^n`
+    /// base grid cells on a side.
+    #[serde(default)]
+    pub level: i32,
+
+    /// The base grid cells that were merged to form this cell, if it was grown.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<Index>,
+
+    /// The fraction of registered points that have an after point within the overlap tolerance.
+    #[serde(default)]
+    pub overlap: Option<f64>,
+
+    /// The prior velocity used to warm-start the registration, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prior: Option<Vector>,
+
+    /// The root mean square distance between the registered points and the after points.
+    #[serde(default)]
+    pub rms: Option<f64>,
+
+    /// The rotation angle of the registration transform, in degrees.
+    #[serde(default)]
+    pub rotation: Option<f64>,
+
+    /// The row of the cell in the grid, in units of the cell's level or, for grown cells, the
+    /// base grid row of its south-west cell.
+    #[serde(default)]
+    pub row: i64,
+
+    /// The final sigma2 of the registration, if it is probabilistic.
+    #[serde(default)]
+    pub sigma2: Option<f64>,
+
+    /// The exact time between the before and after scans, in seconds.
+    #[serde(default)]
+    pub separation: Option<f64>,
+
+    /// The unit of the velocity and its uncertainty.
+    #[serde(default)]
+    pub unit: Unit,
+
+    /// The mean displacement between the points, divided by the time between scans.
     pub velocity: Vector,
 
-    /// The lower-left corner of the velocity cell, x.
+    /// The covariance of the velocity, estimated by bootstrapping.
+    #[serde(default)]
+    pub velocity_covariance: Option<[[f64; 3]; 3]>,
+
+    /// The standard deviation of the velocity, estimated by bootstrapping.
+    #[serde(default)]
+    pub velocity_std: Option<Vector>,
+
+    /// The lower-left corner of the velocity cell, easting.
     pub x: f64,
 
-    /// The lower-left corner of the velocity cell, y.
+    /// The lower-left corner of the velocity cell, northing.
     pub y: f64,
 }
 
+/// A velocity file, as written by `velocities create`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VelocityFile {
+    /// The version of the file format.
+    pub version: u32,
+
+    /// The geometry of the grid used to calculate the velocities.
+    pub geometry: Geometry,
+
+    /// The cells whose velocities could not be calculated.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<Failure>,
+
+    /// The version this file was migrated from, if it was read from an older format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migrated_from: Option<u32>,
+
+    /// The seed for any randomness used to calculate the velocities, such as bootstrapping.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+
+    /// The velocities.
+    pub velocities: Vec<Velocity>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Contents {
+    Current(VelocityFile),
+    Legacy(Vec<Velocity>),
+}
+
+/// The before and after point clouds, shared by every cell in a grid.
+#[derive(Debug)]
+struct Clouds {
+    after: Vec<Point>,
+    before: Vec<Point>,
+}
+
+/// The parameters shared by every cell's velocity calculation.
+#[derive(Clone, Copy, Debug)]
+struct Parameters {
+    bootstrap: usize,
+    datetime: DateTime<Utc>,
+    duration: Duration,
+    geometry: Geometry,
+    overlap_tolerance: f64,
+    seed: u64,
+    unit: Unit,
+}
+
+/// Prior velocities, used to warm-start each cell's registration.
+///
+/// Velocities are stored in meters per second.
+#[derive(Debug)]
+struct Prior {
+    fingerprint: u64,
+    tree: KdTree,
+    velocities: Vec<(Bounds, Vector)>,
+}
+
+/// How cells are refined once their velocities are known.
+#[derive(Clone, Copy, Debug)]
+struct Refinement {
+    gradient: f64,
+    min_grid_size: f64,
+    min_points: usize,
+}
+
 struct Worker {
     id: usize,
 }
@@ -81,13 +359,17 @@ impl Builder {
     pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
         before: P,
         after: Q,
-        grid_size: i64,
+        grid_size: f64,
     ) -> Result<Builder, Error> {
         use las::Reader;
 
+        let geometry = Geometry::new(grid_size)?;
         let before_datetime = super::datetime_from_path(&before)?;
         let after_datetime = super::datetime_from_path(&after)?;
         let duration = after_datetime.signed_duration_since(before_datetime);
+        if duration == Duration::zero() {
+            return Err(ZeroSeparation {}.into());
+        }
         let datetime = before_datetime + duration;
         let before = Reader::from_path(before)?
             .points()
@@ -98,165 +380,520 @@ impl Builder {
         Ok(Builder {
             after: after,
             before: before,
-            datetime: datetime,
-            duration: duration,
-            grid_size: grid_size,
+            grow_healthy: false,
             min_points: 0,
             ngrow: 0,
+            prior: None,
+            parameters: Parameters {
+                bootstrap: 0,
+                datetime: datetime,
+                duration: duration,
+                geometry: geometry,
+                overlap_tolerance: 1.,
+                seed: 0,
+                unit: Unit::MetersPerHour,
+            },
+            refinement: None,
         })
     }
 
+    /// Sets the lower-left corner of the grid's (0, 0) cell.
+    pub fn origin(mut self, x: f64, y: f64) -> Builder {
+        self.parameters.geometry = self.parameters.geometry.origin(x, y);
+        self
+    }
+
+    /// Sets the distance between the lower-left corners of adjacent cells.
+    ///
+    /// A stride smaller than the grid size creates overlapping cells, which share their points.
+    /// Overlapping cells are not grown. Returns an error if the stride isn't positive.
+    pub fn stride(mut self, stride: f64) -> Result<Builder, Error> {
+        self.parameters.geometry = self.parameters.geometry.stride(stride)?;
+        Ok(self)
+    }
+
     /// Sets the number of times this grid should grow.
     pub fn ngrow(mut self, ngrow: usize) -> Builder {
         self.ngrow = ngrow;
         self
     }
 
+    /// Sets whether growing cells may merge neighbors that already have enough points.
+    pub fn grow_healthy(mut self, grow_healthy: bool) -> Builder {
+        self.grow_healthy = grow_healthy;
+        self
+    }
+
+    /// Sets the unit of the calculated velocities.
+    pub fn unit(mut self, unit: Unit) -> Builder {
+        self.parameters.unit = unit;
+        self
+    }
+
+    /// Warm-starts each cell's registration from a prior velocity field.
+    ///
+    /// Each cell's before points are moved by the prior velocity times the time between scans
+    /// before they are registered, and the reported velocity includes that move. The prior
+    /// velocity of a cell is the velocity of the smallest prior cell that contains the cell's
+    /// center or, if there is none, an inverse-distance weighted estimate from the nearest prior
+    /// cells. Refined cells are warm-started from their parent's velocity.
+    pub fn prior(mut self, velocity_file: &VelocityFile) -> Builder {
+        self.prior = Prior::new(velocity_file);
+        self
+    }
+
     /// Sets the minimum number of points allowed in a cell.
     pub fn min_points(mut self, min_points: usize) -> Builder {
         self.min_points = min_points;
         self
     }
 
+    /// Refines cells with steep velocity gradients, down to a minimum grid size.
+    ///
+    /// A cell is split into four children if the horizontal velocity difference between it and
+    /// a neighbor, divided by the distance between them, is at least `gradient`, and if every
+    /// child has at least the minimum number of points. Overlapping cells are not refined.
+    pub fn refine(mut self, min_grid_size: f64, gradient: f64) -> Builder {
+        self.refinement = Some((min_grid_size, gradient));
+        self
+    }
+
+    /// Sets the distance within which a registered point counts as overlapping an after point.
+    pub fn overlap_tolerance(mut self, overlap_tolerance: f64) -> Builder {
+        self.parameters.overlap_tolerance = overlap_tolerance;
+        self
+    }
+
+    /// Sets the number of bootstrap resamples used to estimate each velocity's uncertainty.
+    ///
+    /// Zero, the default, disables bootstrapping.
+    pub fn bootstrap(mut self, bootstrap: usize) -> Builder {
+        self.parameters.bootstrap = bootstrap;
+        self
+    }
+
+    /// Sets the seed for the random number generator used for bootstrapping.
+    pub fn seed(mut self, seed: u64) -> Builder {
+        self.parameters.seed = seed;
+        self
+    }
+
     /// Creates a grid from this builder.
     pub fn into_grid(self) -> Grid {
-        let mut data: HashMap<(i64, i64), Cell> = HashMap::new();
-        let grid_size = self.grid_size;
+        let mut data: BTreeMap<Index, Cell> = BTreeMap::new();
+        let geometry = self.parameters.geometry;
         let min_points = self.min_points;
-        let grid_coordinates =
-            |point: &Point| (point.y as i64 / grid_size, point.x as i64 / grid_size);
-        for point in self.before {
-            let coordinates = grid_coordinates(&point);
-            data.entry(coordinates)
-                .or_insert_with(|| Cell::new(coordinates, grid_size))
-                .before
-                .push(point);
-        }
-        for point in self.after {
-            let coordinates = grid_coordinates(&point);
-            data.entry(coordinates)
-                .or_insert_with(|| Cell::new(coordinates, grid_size))
-                .after
-                .push(point);
+        let new_cell = |index: Index| {
+            let mut cell = Cell::new(
+                Address::root(index),
+                geometry.bounds(index, geometry.cell_size),
+                geometry.cell_size,
+            );
+            cell.members.push(index);
+            cell
+        };
+        for (i, point) in self.before.iter().enumerate() {
+            for index in geometry.containing(point.x, point.y) {
+                data.entry(index)
+                    .or_insert_with(|| new_cell(index))
+                    .before
+                    .push(i);
+            }
+        }
+        for (i, point) in self.after.iter().enumerate() {
+            for index in geometry.containing(point.x, point.y) {
+                data.entry(index)
+                    .or_insert_with(|| new_cell(index))
+                    .after
+                    .push(i);
+            }
         }
+        let mut tree = Quadtree::new();
+        for (index, cell) in data {
+            tree.insert(Address::root(index), cell);
+        }
+        let refinement = match self.refinement {
+            Some(_) if geometry.is_overlapping() => {
+                warn!("Cells overlap, so they will not be refined");
+                None
+            }
+            Some((min_grid_size, gradient)) => {
+                Some(Refinement {
+                    gradient: gradient,
+                    min_grid_size: min_grid_size,
+                    min_points: min_points,
+                })
+            }
+            None => None,
+        };
+        let settings = Settings {
+            bootstrap: self.parameters.bootstrap,
+            datetime: self.parameters.datetime,
+            grow_healthy: self.grow_healthy,
+            method: String::new(),
+            min_points: min_points,
+            ngrow: self.ngrow,
+            overlap_tolerance: self.parameters.overlap_tolerance,
+            prior: self.prior.as_ref().map(|prior| prior.fingerprint),
+            refinement: self.refinement,
+            separation: self.parameters.separation(),
+            unit: self.parameters.unit,
+        };
         let mut grid = Grid {
-            data: data,
-            datetime: self.datetime,
-            duration: self.duration,
+            checkpoint: None,
+            failures: Vec::new(),
+            clouds: Arc::new(Clouds {
+                after: self.after,
+                before: self.before,
+            }),
+            parameters: self.parameters,
+            refinement: refinement,
+            settings: settings,
+            tracker: None,
+            tree: tree,
         };
-        for _ in 0..self.ngrow {
-            info!("Growing cells");
-            let n = grid.grow(min_points);
-            info!("{} cells grown", n);
+        if geometry.is_overlapping() {
+            if self.ngrow > 0 {
+                warn!("Cells overlap, so they will not be grown");
+            }
+        } else {
+            for _ in 0..self.ngrow {
+                info!("Growing cells");
+                let n = grid.grow(min_points, self.grow_healthy);
+                info!("{} cells grown", n);
+            }
         }
-        let before = grid.data.len();
+        let before = grid.len();
         grid.cull(min_points);
-        let after = grid.data.len();
+        let after = grid.len();
         info!("{} cells accepted, {} cells culled", after, before - after);
+        if let Some(prior) = self.prior {
+            let mut missing = 0;
+            for address in grid.tree.addresses() {
+                let cell = grid.tree.get_mut(&address).unwrap();
+                cell.prior = prior.estimate(&cell.bounds);
+                if cell.prior.is_none() {
+                    missing += 1;
+                }
+            }
+            if missing > 0 {
+                warn!("{} cells have no prior velocity", missing);
+            }
+        }
         grid
     }
 }
 
 impl Grid {
-    /// Grow any under-populated grid cells.
-    pub fn grow(&mut self, min_points: usize) -> usize {
-        let mut count = 0;
-        for coordinate in self.coordinates() {
-            if self.cell(coordinate)
-                .map(|c| c.is_too_small(min_points))
-                .unwrap_or(false)
-            {
-                self.grow_cell(coordinate);
-                count += 1;
+    /// Returns this grid's geometry.
+    pub fn geometry(&self) -> Geometry {
+        self.parameters.geometry
+    }
+
+    /// Grow any under-populated grid cells, returning the number of cells that were grown.
+    ///
+    /// Each under-populated cell is merged with its neighbors into one of the four two-by-two
+    /// blocks of same-sized cells that contain it. The block that reaches `min_points` with the
+    /// fewest points is chosen or, if no block reaches `min_points`, the block with the most
+    /// points. Neighbors that already have enough points are only merged if `healthy` is true.
+    pub fn grow(&mut self, min_points: usize, healthy: bool) -> usize {
+        let cell_size = self.parameters.geometry.cell_size;
+        let mut owners = HashMap::new();
+        for (address, cell) in self.tree.leaves() {
+            let anchor = address.root_index();
+            for index in block(anchor, cell.span(cell_size)) {
+                owners.insert(index, anchor);
+            }
+        }
+        let mut anchors = self.tree
+            .addresses()
+            .into_iter()
+            .filter(|address| address.level <= 0)
+            .map(|address| (address.root_index(), address))
+            .collect::<HashMap<_, _>>();
+        let mut grown = HashSet::new();
+        for address in self.tree.addresses().into_iter().filter(|a| a.level <= 0) {
+            let index = address.root_index();
+            let span = match self.tree.get(&address) {
+                Some(cell) if cell.is_too_small(min_points) && !grown.contains(&index) => {
+                    cell.span(cell_size)
+                }
+                _ => continue,
+            };
+            let mut best: Option<(Index, usize, usize)> = None;
+            for &(dr, dc) in [(0, 0), (0, 1), (1, 0), (1, 1)].iter() {
+                let anchor = Index {
+                    row: index.row - dr * span,
+                    col: index.col - dc * span,
+                };
+                let members = block(anchor, 2)
+                    .into_iter()
+                    .map(|i| scale(anchor, i, span))
+                    .collect::<Vec<_>>();
+                let mut valid = block(anchor, 2 * span).into_iter().all(|i| {
+                    owners.get(&i).map(|o| members.contains(o)).unwrap_or(true)
+                });
+                let mut before = 0;
+                let mut after = 0;
+                for cell in members.iter().filter_map(|member| {
+                    anchors.get(member).and_then(|address| self.tree.get(address))
+                })
+                {
+                    if cell.span(cell_size) != span ||
+                        (!healthy && !cell.is_too_small(min_points))
+                    {
+                        valid = false;
+                    }
+                    before += cell.before.len();
+                    after += cell.after.len();
+                }
+                if valid &&
+                    best.map(|(_, b, a)| {
+                        is_better_block((before, after), (b, a), min_points)
+                    }).unwrap_or(true)
+                {
+                    best = Some((anchor, before, after));
+                }
+            }
+            if let Some((anchor, _, _)) = best {
+                for member in self.merge(anchor, span) {
+                    anchors.remove(&member);
+                }
+                anchors.insert(anchor, Address::grown(anchor, 2 * span));
+                for index in block(anchor, 2 * span) {
+                    owners.insert(index, anchor);
+                }
+                grown.insert(anchor);
             }
         }
-        count
+        grown.len()
+    }
+
+    /// Returns the seed for this grid's random number generators.
+    pub fn seed(&self) -> u64 {
+        self.parameters.seed
+    }
+
+    /// Streams each cell's result to a checkpoint file as soon as it is calculated.
+    ///
+    /// The registration method, as passed to `registration::from_name`, is recorded with the
+    /// grid's settings. If the checkpoint file already exists, it must have been written for a
+    /// grid with the same geometry, seed, and settings, and any cells with results in it are not
+    /// calculated again.
+    pub fn checkpoint<P: AsRef<Path>>(mut self, path: P, method: &str) -> Result<Grid, Error> {
+        self.settings.method = method.to_string();
+        self.checkpoint = Some(Arc::new(Checkpoint::open(
+            path,
+            self.geometry(),
+            self.seed(),
+            &self.settings,
+        )?));
+        Ok(self)
+    }
+
+    /// Reports the progress of the velocity calculation to an observer.
+    pub fn progress<P: Progress + 'static>(mut self, progress: P) -> Grid {
+        self.tracker = Some(Tracker::new(Box::new(progress)));
+        self
     }
 
-    /// Calculates velocities for each cell in this grid.
-    pub fn calculate_velocities<T: Into<Option<usize>>>(
+    /// Calculates velocities for each cell in this grid with the given registration.
+    ///
+    /// If the grid was built with refinement, cells whose velocity differs sharply from their
+    /// neighbors' are split into four children and the children's velocities are calculated in
+    /// turn, until the children would be too small or too sparse.
+    ///
+    /// Every cell has a result, including cells that were culled for having too few points. The
+    /// results are sorted by cell address.
+    ///
+    /// Cells are scheduled most expensive first over `num_threads` workers, which defaults to
+    /// the number of CPUs.
+    pub fn calculate_velocities<T, R>(
         self,
         num_threads: T,
-        rigid: Rigid,
-    ) -> Vec<Result<Velocity, Error>> {
-        use std::thread;
-
-        let num_threads = num_threads.into().unwrap_or(1);
+        registration: R,
+    ) -> Vec<Result<Velocity, Failure>>
+    where
+        T: Into<Option<usize>>,
+        R: Registration + 'static,
+    {
+        let num_threads = num_threads.into().unwrap_or_else(num_cpus::get);
         assert!(num_threads > 0);
-        let mut handles = Vec::new();
-        let grid = Arc::new(Mutex::new(self));
-        for i in 0..num_threads {
-            let grid = grid.clone();
-            let rigid = rigid.clone();
-            let handle = thread::spawn(move || {
-                let worker = Worker { id: i };
-                worker.start(grid, rigid)
-            });
-            handles.push(handle);
+        let registration = Arc::new(registration);
+        let Grid {
+            checkpoint,
+            clouds,
+            failures,
+            parameters,
+            refinement,
+            tracker,
+            mut tree,
+        } = self;
+        let tracker = tracker.map(|tracker| Arc::new(tracker.started()));
+        let mut results = BTreeMap::new();
+        let mut pending = tree.addresses();
+        while !pending.is_empty() {
+            if let Some(ref tracker) = tracker {
+                tracker.queued(pending.len());
+            }
+            let mut cells = Vec::new();
+            for address in pending.iter() {
+                if let Some(result) = checkpoint.as_ref().and_then(|c| c.take(address)) {
+                    if let Some(ref tracker) = tracker {
+                        tracker.finished(Outcome::Resumed);
+                    }
+                    results.insert(*address, result);
+                } else if let Some(cell) = tree.remove(address) {
+                    cells.push(cell);
+                }
+            }
+            for (cell, result) in run(
+                cells,
+                num_threads,
+                &clouds,
+                parameters,
+                &registration,
+                &checkpoint,
+                &tracker,
+            )
+            {
+                results.insert(cell.address, result);
+                tree.insert(cell.address, cell);
+            }
+            pending = match refinement {
+                Some(ref refinement) => {
+                    refinement.refine(&mut tree, &mut results, &pending, &clouds)
+                }
+                None => Vec::new(),
+            };
         }
-        let mut velocities = Vec::new();
-        for handle in handles {
-            let v = handle.join().unwrap();
-            velocities.extend(v);
+        if let Some(ref tracker) = tracker {
+            tracker.finish();
         }
-        velocities
+        for failure in failures {
+            results.insert(failure.address(), Err(failure));
+        }
+        results.into_iter().map(|(_, result)| result).collect()
     }
 
+    /// Removes cells with too few points, recording them as failures.
     fn cull(&mut self, min_points: usize) {
-        self.data.retain(|_, cell| !cell.is_too_small(min_points))
-    }
-
-    fn cell(&self, coordinate: (i64, i64)) -> Option<&Cell> {
-        self.data.get(&coordinate)
-    }
-
-    fn pop(&mut self) -> Option<Cell> {
-        if let Some(&key) = self.data.keys().next() {
-            self.data.remove(&key)
-        } else {
-            None
-        }
+        let culled = self.tree
+            .leaves()
+            .filter(|&(_, cell)| cell.is_too_small(min_points))
+            .map(|(_, cell)| {
+                Failure::new(
+                    cell,
+                    FailureKind::TooFewPoints,
+                    format!("fewer than {} points", min_points),
+                )
+            })
+            .collect::<Vec<_>>();
+        self.failures.extend(culled);
+        self.tree.retain(|_, cell| !cell.is_too_small(min_points))
     }
 
     fn len(&self) -> usize {
-        self.data.len()
+        self.tree.len()
     }
 
-    fn coordinates(&self) -> Vec<(i64, i64)> {
-        let mut coordinates = self.data.keys().map(|&k| k).collect::<Vec<_>>();
-        coordinates.sort();
-        coordinates
+    /// Merges the two-by-two block of cells with this span, anchored at `anchor`, into one grown
+    /// cell, returning the anchors of the cells that were merged.
+    fn merge(&mut self, anchor: Index, span: i64) -> Vec<Index> {
+        let geometry = self.parameters.geometry;
+        let grid_size = geometry.cell_size * (2 * span) as f64;
+        let mut cell = Cell::new(
+            Address::grown(anchor, 2 * span),
+            geometry.bounds(anchor, grid_size),
+            grid_size,
+        );
+        let mut merged = Vec::new();
+        for member in block(anchor, 2).into_iter().map(|i| scale(anchor, i, span)) {
+            if let Some(other) = self.tree.remove(&Address::grown(member, span)) {
+                cell.consume(other);
+                merged.push(member);
+            }
+        }
+        self.tree.insert(cell.address, cell);
+        merged
     }
+}
 
-    fn grow_cell(&mut self, coordinate: (i64, i64)) {
-        let mut cell = self.data.remove(&coordinate).expect(
-            "grow_cell called but cell does not exist",
-        );
-        cell.grid_size *= 2;
-        let (r, c) = coordinate;
-        for k in [(r + 1, c), (r, c + 1), (r + 1, c + 1)].into_iter() {
-            while self.data
-                .get(k)
-                .map(|c| c.grid_size < cell.grid_size / 2)
-                .unwrap_or(false)
+impl Refinement {
+    /// Splits the candidate cells that have a steep velocity gradient, returning the addresses
+    /// of the new children.
+    ///
+    /// The gradient of a cell is the largest horizontal velocity difference between it and a
+    /// touching cell, divided by the distance between their centers.
+    fn refine(
+        &self,
+        tree: &mut Quadtree<Cell>,
+        results: &mut BTreeMap<Address, Result<Velocity, Failure>>,
+        candidates: &[Address],
+        clouds: &Clouds,
+    ) -> Vec<Address> {
+        let velocities = results
+            .iter()
+            .filter_map(|(&address, result)| {
+                result.as_ref().ok().map(|v| (address, v.bounds, v.velocity))
+            })
+            .collect::<Vec<_>>();
+        let mut to_split = Vec::new();
+        for &(address, bounds, velocity) in velocities.iter() {
+            if !candidates.contains(&address) ||
+                bounds.max_x - bounds.min_x < 2. * self.min_grid_size
             {
-                self.grow_cell(*k);
+                continue;
             }
-            if let Some(other) = self.data.remove(k) {
-                cell.consume(other);
+            let center = bounds.center();
+            let gradient = velocities
+                .iter()
+                .filter(|&&(other, other_bounds, _)| {
+                    other != address && bounds.touches(&other_bounds)
+                })
+                .map(|&(_, other_bounds, other_velocity)| {
+                    let other_center = other_bounds.center();
+                    let distance = ((center.0 - other_center.0).powi(2) +
+                                        (center.1 - other_center.1).powi(2))
+                        .sqrt();
+                    (velocity - other_velocity).xy() / distance
+                })
+                .fold(0., f64::max);
+            if gradient >= self.gradient {
+                to_split.push(address);
+            }
+        }
+        let mut children = Vec::new();
+        for address in to_split {
+            let min_points = self.min_points;
+            if tree.split(&address, |cell| cell.split(clouds, min_points)) {
+                let prior = match results.remove(&address) {
+                    Some(Ok(velocity)) => Some(velocity.velocity / velocity.unit.seconds()),
+                    _ => None,
+                };
+                for child in address.children().iter() {
+                    if prior.is_some() {
+                        tree.get_mut(child).unwrap().prior = prior;
+                    }
+                    children.push(*child);
+                }
             }
         }
-        self.data.insert(coordinate, cell);
+        info!("{} cells refined", children.len() / 4);
+        children
     }
 }
 
 impl Cell {
-    fn new(coordinates: (i64, i64), grid_size: i64) -> Cell {
+    fn new(address: Address, bounds: Bounds, grid_size: f64) -> Cell {
         Cell {
+            address: address,
             after: Vec::new(),
             before: Vec::new(),
-            coordinates: coordinates,
+            bounds: bounds,
             grid_size: grid_size,
+            members: Vec::new(),
+            prior: None,
         }
     }
 
@@ -265,65 +902,807 @@ impl Cell {
     }
 
     fn consume(&mut self, other: Cell) {
-        assert_eq!(self.grid_size, other.grid_size * 2);
+        assert_eq!(self.grid_size, other.grid_size * 2.);
         self.before.extend(other.before);
         self.after.extend(other.after);
+        self.members.extend(other.members);
+    }
+
+    /// Estimates the relative cost of calculating this cell's velocity.
+    ///
+    /// Registration compares every before point with every after point, so the cost grows with
+    /// the product of the point counts.
+    fn cost(&self) -> f64 {
+        self.before.len() as f64 * self.after.len() as f64
+    }
+
+    /// Moves points by this cell's prior velocity over the time between scans.
+    ///
+    /// Since the moved points are registered in place of the original points, displacements
+    /// measured from the original points include the prior move.
+    fn warm_start(&self, points: &Points, separation: f64) -> Points {
+        let mut points = points.clone();
+        if let Some(prior) = self.prior {
+            let offset = prior * separation;
+            for i in 0..points.nrows() {
+                points[(i, 0)] += offset.x;
+                points[(i, 1)] += offset.y;
+                points[(i, 2)] += offset.z;
+            }
+        }
+        points
+    }
+
+    /// Returns the number of base grid cells along each side of this cell.
+    fn span(&self, cell_size: f64) -> i64 {
+        (self.grid_size / cell_size).round() as i64
+    }
+
+    /// Splits this cell into its four quadtree children, or returns it unchanged if any child
+    /// would have too few points or if it was grown.
+    fn split(self, clouds: &Clouds, min_points: usize) -> Result<Vec<Cell>, Cell> {
+        if self.address.level < 0 {
+            return Err(self);
+        }
+        let half = self.grid_size / 2.;
+        let (mid_x, mid_y) = self.bounds.center();
+        let mut children = self.address
+            .children()
+            .iter()
+            .enumerate()
+            .map(|(quadrant, &address)| {
+                let x = self.bounds.min_x + half * (quadrant % 2) as f64;
+                let y = self.bounds.min_y + half * (quadrant / 2) as f64;
+                let mut child = Cell::new(address, Bounds::new(x, y, half), half);
+                child.prior = self.prior;
+                child
+            })
+            .collect::<Vec<_>>();
+        let quadrant = |point: &Point| {
+            (if point.x >= mid_x { 1 } else { 0 }) + (if point.y >= mid_y { 2 } else { 0 })
+        };
+        for &i in self.before.iter() {
+            children[quadrant(&clouds.before[i])].before.push(i);
+        }
+        for &i in self.after.iter() {
+            children[quadrant(&clouds.after[i])].after.push(i);
+        }
+        if children.iter().any(|child| child.is_too_small(min_points)) {
+            Err(self)
+        } else {
+            Ok(children)
+        }
     }
 
     fn calculate_velocity(
         &self,
-        rigid: &Rigid,
-        datetime: DateTime<Utc>,
-        duration: Duration,
+        clouds: &Clouds,
+        registration: &Registration,
+        parameters: &Parameters,
     ) -> Result<Velocity, Error> {
-        let before = super::matrix_from_points(&self.before);
-        let after = super::matrix_from_points(&self.after);
-        let run = rigid.register(&after, &before)?;
-        let displacement = run.moved - &before;
-        if run.converged {
-            Ok(Velocity {
-                after_points: after.nrows(),
-                before_points: before.nrows(),
-                center_of_gravity: super::center_of_gravity(&before),
-                datetime: datetime,
-                grid_size: self.grid_size,
+        let separation = parameters.separation();
+        let before = matrix(&clouds.before, &self.before);
+        let after = matrix(&clouds.after, &self.after);
+        let run = registration.register(&after, &self.warm_start(&before, separation))?;
+        if !run.converged {
+            return Err(DidNotConverge {
                 iterations: run.iterations,
-                x: (self.coordinates.0 * self.grid_size) as f64,
-                y: (self.coordinates.1 * self.grid_size) as f64,
-                velocity: super::center_of_gravity(&displacement) / duration.num_hours() as f64,
+                rms: Quality::new(&after, &run.moved, parameters.overlap_tolerance).rms,
+                sigma2: run.sigma2,
+            }.into());
+        }
+        let displacement = super::center_of_gravity(&(&run.moved - &before));
+        let center_of_gravity = super::center_of_gravity(&before);
+        let quality = Quality::new(&after, &run.moved, parameters.overlap_tolerance);
+        let (bootstrap_samples, velocity_covariance) = if parameters.bootstrap > 0 {
+            let samples = self.bootstrap(registration, parameters, &before, &after);
+            (Some(samples.len()), Vector::covariance(&samples))
+        } else {
+            (None, None)
+        };
+        Ok(Velocity {
+            after_points: after.nrows(),
+            before_points: before.nrows(),
+            bootstrap_samples: bootstrap_samples,
+            bounds: self.bounds,
+            center_of_gravity: center_of_gravity,
+            centroid_displacement: Some(super::center_of_gravity(&after) - center_of_gravity),
+            col: self.address.col,
+            datetime: parameters.datetime,
+            displacement: Some(displacement),
+            flags: Vec::new(),
+            grid_size: self.grid_size,
+            iterations: run.iterations,
+            level: self.address.level,
+            members: if self.address.level < 0 {
+                self.members.clone()
+            } else {
+                Vec::new()
+            },
+            overlap: Some(quality.overlap),
+            prior: self.prior.map(|prior| prior * parameters.unit.seconds()),
+            rms: Some(quality.rms),
+            rotation: Some(run.rotation()),
+            row: self.address.row,
+            separation: Some(separation),
+            sigma2: run.sigma2,
+            unit: parameters.unit,
+            velocity: displacement / parameters.unit.from_seconds(separation),
+            velocity_covariance: velocity_covariance,
+            velocity_std: velocity_covariance.map(|c| {
+                Vector {
+                    x: c[0][0].sqrt(),
+                    y: c[1][1].sqrt(),
+                    z: c[2][2].sqrt(),
+                }
+            }),
+            x: self.bounds.min_x,
+            y: self.bounds.min_y,
+        })
+    }
+
+    /// Resamples the before and after points with replacement and re-registers them.
+    ///
+    /// Returns the velocity of every converged resample. Resamples that fail to register, or
+    /// don't converge, are skipped. The resamples for a cell run on the
+    /// worker thread that picked up the cell, so they share the grid's worker pool.
+    fn bootstrap(
+        &self,
+        registration: &Registration,
+        parameters: &Parameters,
+        before: &Points,
+        after: &Points,
+    ) -> Vec<Vector> {
+        let mut rng = rng(parameters.seed, self.address);
+        let period = parameters.unit.from_seconds(parameters.separation());
+        let mut samples = Vec::with_capacity(parameters.bootstrap);
+        for _ in 0..parameters.bootstrap {
+            let before = resample(before, &mut rng);
+            let after = resample(after, &mut rng);
+            let moving = self.warm_start(&before, parameters.separation());
+            match registration.register(&after, &moving) {
+                Ok(ref run) if run.converged => {
+                    samples.push(super::center_of_gravity(&(&run.moved - &before)) / period);
+                }
+                _ => {}
+            }
+        }
+        samples
+    }
+}
+
+impl Prior {
+    fn new(velocity_file: &VelocityFile) -> Option<Prior> {
+        use std::cmp::Ordering;
+
+        let mut velocities = velocity_file
+            .velocities
+            .iter()
+            .map(|v| (v.bounds, v.velocity / v.unit.seconds()))
+            .collect::<Vec<_>>();
+        if velocities.is_empty() {
+            warn!("The prior velocity file has no velocities");
+            return None;
+        }
+        velocities.sort_by(|a, b| {
+            (a.0.max_x - a.0.min_x)
+                .partial_cmp(&(b.0.max_x - b.0.min_x))
+                .unwrap_or(Ordering::Equal)
+        });
+        let centers = velocities
+            .iter()
+            .map(|&(bounds, _)| {
+                let (x, y) = bounds.center();
+                [x, y, 0.]
             })
+            .collect();
+        let fingerprint = velocities.iter().fold(0, |state, &(bounds, velocity)| {
+            [
+                bounds.min_x,
+                bounds.min_y,
+                bounds.max_x,
+                bounds.max_y,
+                velocity.x,
+                velocity.y,
+                velocity.z,
+            ].iter()
+                .fold(state, |state, value| splitmix64(state ^ value.to_bits()))
+        });
+        Some(Prior {
+            fingerprint: fingerprint,
+            tree: KdTree::new(centers, 2),
+            velocities: velocities,
+        })
+    }
+
+    /// Estimates the prior velocity of a cell.
+    fn estimate(&self, bounds: &Bounds) -> Option<Vector> {
+        let (x, y) = bounds.center();
+        if let Some(&(_, velocity)) = self.velocities.iter().find(|&&(prior_bounds, _)| {
+            prior_bounds.contains(x, y)
+        })
+        {
+            return Some(velocity);
+        }
+        let radius = 2. * (bounds.max_x - bounds.min_x).max(bounds.max_y - bounds.min_y);
+        let mut weight = 0.;
+        let mut sum = Vector::default();
+        for (i, distance2) in self.tree.nearest_k(&[x, y, 0.], 4) {
+            if distance2.sqrt() > radius {
+                continue;
+            }
+            let w = 1. / distance2.max(1e-12);
+            sum = sum + self.velocities[i].1 * w;
+            weight += w;
+        }
+        if weight > 0. {
+            Some(sum / weight)
         } else {
-            Err(DidNotConverge {}.into())
+            None
         }
     }
 }
 
-impl Worker {
-    fn start(&self, grid: Arc<Mutex<Grid>>, rigid: Rigid) -> Vec<Result<Velocity, Error>> {
-        let (datetime, duration) = {
-            let grid = grid.lock().unwrap();
-            (grid.datetime, grid.duration)
+impl Velocity {
+    /// Returns true if this velocity should be used, i.e. it wasn't flagged as an outlier or
+    /// flagged velocities are included.
+    pub fn is_included(&self, include_flagged: bool) -> bool {
+        include_flagged || self.flags.is_empty()
+    }
+
+    /// Returns the quadtree address of this velocity's cell.
+    pub fn address(&self) -> Address {
+        Address {
+            level: self.level,
+            row: self.row,
+            col: self.col,
+        }
+    }
+}
+
+impl Failure {
+    fn new(cell: &Cell, kind: FailureKind, message: String) -> Failure {
+        Failure {
+            after_points: cell.after.len(),
+            before_points: cell.before.len(),
+            bounds: cell.bounds,
+            col: cell.address.col,
+            grid_size: cell.grid_size,
+            iterations: None,
+            kind: kind,
+            level: cell.address.level,
+            message: message,
+            rms: None,
+            row: cell.address.row,
+            sigma2: None,
+        }
+    }
+
+    fn from_error(cell: &Cell, err: &Error) -> Failure {
+        match err.downcast_ref::<DidNotConverge>() {
+            Some(did_not_converge) => {
+                Failure {
+                    iterations: Some(did_not_converge.iterations),
+                    rms: Some(did_not_converge.rms),
+                    sigma2: did_not_converge.sigma2,
+                    ..Failure::new(cell, FailureKind::NotConverged, err.to_string())
+                }
+            }
+            None => Failure::new(cell, FailureKind::Registration, err.to_string()),
+        }
+    }
+
+    /// Returns the quadtree address of the failed cell.
+    pub fn address(&self) -> Address {
+        Address {
+            level: self.level,
+            row: self.row,
+            col: self.col,
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FailureKind::NotConverged => write!(f, "not converged"),
+            FailureKind::Registration => write!(f, "registration error"),
+            FailureKind::TooFewPoints => write!(f, "too few points"),
+            FailureKind::Panic => write!(f, "panic"),
+        }
+    }
+}
+
+impl Parameters {
+    /// Returns the exact time between the before and after scans, in seconds.
+    fn separation(&self) -> f64 {
+        super::seconds(self.duration)
+    }
+}
+
+impl Unit {
+    /// Returns the number of seconds in this unit's time period.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::velocities::Unit;
+    /// assert_eq!(3600., Unit::MetersPerHour.seconds());
+    /// assert_eq!(86400., Unit::MetersPerDay.seconds());
+    /// ```
+    pub fn seconds(&self) -> f64 {
+        match *self {
+            Unit::MetersPerHour => 3600.,
+            Unit::MetersPerDay => 86_400.,
+            Unit::MetersPerYear => 365.25 * 86_400.,
+        }
+    }
+
+    /// Converts a number of seconds into this unit's time periods.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ape::velocities::Unit;
+    /// assert_eq!(1.5, Unit::MetersPerHour.from_seconds(5400.));
+    /// ```
+    pub fn from_seconds(&self, seconds: f64) -> f64 {
+        seconds / self.seconds()
+    }
+}
+
+impl Default for Unit {
+    fn default() -> Unit {
+        Unit::MetersPerHour
+    }
+}
+
+impl FromStr for Unit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Unit, Error> {
+        match s {
+            "m/h" => Ok(Unit::MetersPerHour),
+            "m/day" => Ok(Unit::MetersPerDay),
+            "m/yr" => Ok(Unit::MetersPerYear),
+            _ => Err(InvalidUnit(s.to_string()).into()),
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Unit::MetersPerHour => write!(f, "m/h"),
+            Unit::MetersPerDay => write!(f, "m/day"),
+            Unit::MetersPerYear => write!(f, "m/yr"),
+        }
+    }
+}
+
+impl VelocityFile {
+    /// Creates a new velocity file in the current format.
+    pub fn new(geometry: Geometry, velocities: Vec<Velocity>) -> VelocityFile {
+        VelocityFile {
+            version: VERSION,
+            geometry: geometry,
+            failures: Vec::new(),
+            migrated_from: None,
+            seed: None,
+            velocities: velocities,
+        }.sorted()
+    }
+
+    /// Creates a new velocity file from the results of `Grid::calculate_velocities`, keeping the
+    /// failures alongside the velocities.
+    pub fn from_results(
+        geometry: Geometry,
+        results: Vec<Result<Velocity, Failure>>,
+    ) -> VelocityFile {
+        let mut velocity_file = VelocityFile::new(geometry, Vec::new());
+        for result in results {
+            match result {
+                Ok(velocity) => velocity_file.velocities.push(velocity),
+                Err(failure) => velocity_file.failures.push(failure),
+            }
+        }
+        velocity_file.sorted()
+    }
+
+    /// Sorts the velocities and failures by their position in the grid, south to north and then
+    /// west to east, so the same results are always written in the same order.
+    pub fn sorted(mut self) -> VelocityFile {
+        self.velocities.sort_by_key(|velocity| sort_key(velocity.address()));
+        self.failures.sort_by_key(|failure| sort_key(failure.address()));
+        self
+    }
+
+    /// Returns the velocities that should be used, skipping those flagged as outliers unless
+    /// `include_flagged` is true.
+    pub fn included(&self, include_flagged: bool) -> Vec<&Velocity> {
+        self.velocities
+            .iter()
+            .filter(|velocity| velocity.is_included(include_flagged))
+            .collect()
+    }
+
+    /// Returns the bounds of all of the velocities' cells, or `None` if there are no velocities.
+    pub fn bounds(&self) -> Option<Bounds> {
+        let mut velocities = self.velocities.iter();
+        let first = velocities.next()?.bounds;
+        Some(velocities.fold(first, |bounds, velocity| {
+            Bounds {
+                min_x: bounds.min_x.min(velocity.bounds.min_x),
+                min_y: bounds.min_y.min(velocity.bounds.min_y),
+                max_x: bounds.max_x.max(velocity.bounds.max_x),
+                max_y: bounds.max_y.max(velocity.bounds.max_y),
+            }
+        }))
+    }
+
+    /// Returns a map of the base grid, one string per row from north to south.
+    ///
+    /// Each character is one base grid cell: `.` if every velocity covering the cell was
+    /// calculated, a letter for the first kind of failure in the cell (`c` not converged, `r`
+    /// registration error, `p` too few points, `!` panic), or a space if the cell has no results
+    /// at all. Grown cells mark every base grid cell they cover.
+    pub fn status_map(&self) -> Vec<String> {
+        let mut cells = BTreeMap::new();
+        for velocity in self.velocities.iter() {
+            for index in velocity.address().base_indices() {
+                cells.entry(index).or_insert('.');
+            }
+        }
+        for failure in self.failures.iter() {
+            let symbol = match failure.kind {
+                FailureKind::NotConverged => 'c',
+                FailureKind::Registration => 'r',
+                FailureKind::TooFewPoints => 'p',
+                FailureKind::Panic => '!',
+            };
+            for index in failure.address().base_indices() {
+                let entry = cells.entry(index).or_insert(symbol);
+                if *entry == '.' {
+                    *entry = symbol;
+                }
+            }
+        }
+        if cells.is_empty() {
+            return Vec::new();
+        }
+        let min_row = cells.keys().map(|index| index.row).min().unwrap();
+        let max_row = cells.keys().map(|index| index.row).max().unwrap();
+        let min_col = cells.keys().map(|index| index.col).min().unwrap();
+        let max_col = cells.keys().map(|index| index.col).max().unwrap();
+        (min_row..(max_row + 1))
+            .rev()
+            .map(|row| {
+                (min_col..(max_col + 1))
+                    .map(|col| {
+                        cells.get(&Index { row: row, col: col }).cloned().unwrap_or(' ')
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Reads a velocity file, migrating older formats to the current one.
+    ///
+    /// Files written before the grid geometry was recorded are a bare list of velocities with
+    /// `x` and `y` swapped. Migrated files are flagged with `migrated_from`. Cells in version zero
+    /// files that touch an axis may hold points from both sides of it, so they are warned about.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<VelocityFile, Error> {
+        let mut velocity_file = match super::read_json::<Contents, _>(&path)? {
+            Contents::Current(velocity_file) => velocity_file,
+            Contents::Legacy(velocities) => VelocityFile::from_legacy(velocities)?,
         };
-        let mut velocities = Vec::new();
-        while let Some((cell, remaining)) =
-            {
-                let mut grid = grid.lock().unwrap();
-                grid.pop().map(|cell| (cell, grid.len()))
+        if velocity_file.version < VERSION {
+            warn!(
+                "{} is a version {} velocity file, migrating",
+                path.as_ref().display(),
+                velocity_file.version
+            );
+            velocity_file.migrate();
+        }
+        Ok(velocity_file)
+    }
+
+    /// Writes this velocity file to a path.
+    pub fn to_path<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        super::write_json(self, path)
+    }
+
+    fn from_legacy(velocities: Vec<Velocity>) -> Result<VelocityFile, Error> {
+        let cell_size = velocities.iter().map(|v| v.grid_size).fold(
+            None,
+            |min: Option<f64>, grid_size| {
+                Some(min.map(|m| m.min(grid_size)).unwrap_or(grid_size))
+            },
+        );
+        Ok(VelocityFile {
+            version: 0,
+            geometry: Geometry::new(cell_size.unwrap_or(100.))?,
+            failures: Vec::new(),
+            migrated_from: None,
+            seed: None,
+            velocities: velocities,
+        })
+    }
+
+    fn migrate(&mut self) {
+        use std::mem;
+
+        if self.version == 0 {
+            let mut ambiguous = 0;
+            for velocity in self.velocities.iter_mut() {
+                // Legacy corners were scaled by the cell's grid size, which grown cells doubled.
+                let scale = self.geometry.cell_size / velocity.grid_size;
+                mem::swap(&mut velocity.x, &mut velocity.y);
+                velocity.x *= scale;
+                velocity.y *= scale;
+                let index = self.geometry.index(velocity.x, velocity.y);
+                let span = (velocity.grid_size / self.geometry.cell_size).round() as i64;
+                let address = if span > 1 && span.count_ones() == 1 {
+                    Address::grown(index, span)
+                } else {
+                    Address::root(index)
+                };
+                velocity.level = address.level;
+                velocity.row = address.row;
+                velocity.col = address.col;
+                if address.level < 0 {
+                    velocity.members = address.base_indices();
+                }
+                if velocity.x == 0. || velocity.y == 0. {
+                    ambiguous += 1;
+                }
             }
-        {
-            info!(
-                "#{} [{} remaining]: Got cell ({}, {}), size: {}, before: {}, after: {}",
+            if ambiguous > 0 {
+                warn!(
+                    "{} migrated cells touch an axis and may include points from both sides of it",
+                    ambiguous
+                );
+            }
+        }
+        if self.version <= 1 {
+            for velocity in self.velocities.iter_mut() {
+                velocity.bounds = Bounds::new(velocity.x, velocity.y, velocity.grid_size);
+            }
+        }
+        self.migrated_from = Some(self.version);
+        self.version = VERSION;
+    }
+}
+
+impl Worker {
+    fn start<R: Registration>(
+        &self,
+        scheduler: Arc<Scheduler<Cell>>,
+        clouds: Arc<Clouds>,
+        parameters: Parameters,
+        registration: Arc<R>,
+        checkpoint: Option<Arc<Checkpoint>>,
+        tracker: Option<Arc<Tracker>>,
+    ) -> Vec<(Cell, Result<Velocity, Failure>)> {
+        let mut velocities = Vec::new();
+        while let Some((cell, remaining)) = scheduler.next(self.id) {
+            debug!(
+                "#{} [{} remaining]: Got cell ({}, {}) at level {}, size: {}, before: {}, after: {}",
                 self.id,
                 remaining,
-                cell.coordinates.0,
-                cell.coordinates.1,
+                cell.address.row,
+                cell.address.col,
+                cell.address.level,
                 cell.grid_size,
                 cell.before.len(),
                 cell.after.len(),
             );
-            velocities.push(cell.calculate_velocity(&rigid, datetime, duration));
+            let velocity = match panic::catch_unwind(AssertUnwindSafe(|| {
+                cell.calculate_velocity(&clouds, &*registration, &parameters)
+            })) {
+                Ok(velocity) => velocity.map_err(|err| Failure::from_error(&cell, &err)),
+                Err(payload) => {
+                    warn!(
+                        "#{} panicked on cell ({}, {}) at level {}",
+                        self.id,
+                        cell.address.row,
+                        cell.address.col,
+                        cell.address.level
+                    );
+                    Err(Failure::new(&cell, FailureKind::Panic, panic_message(payload)))
+                }
+            };
+            if let Some(ref checkpoint) = checkpoint {
+                if let Err(err) = checkpoint.record(cell.address, &velocity) {
+                    warn!("#{} could not write to the checkpoint: {}", self.id, err);
+                }
+            }
+            if let Some(ref tracker) = tracker {
+                tracker.finished(match velocity {
+                    Ok(_) => Outcome::Converged,
+                    Err(Failure { kind: FailureKind::NotConverged, .. }) => Outcome::NotConverged,
+                    Err(_) => Outcome::Failed,
+                });
+            }
+            velocities.push((cell, velocity));
         }
-        info!("#{} is done", self.id);
+        debug!("#{} is done", self.id);
         velocities
     }
 }
+
+/// Calculates the velocities of some cells on a pool of worker threads.
+fn run<R: Registration + 'static>(
+    cells: Vec<Cell>,
+    num_threads: usize,
+    clouds: &Arc<Clouds>,
+    parameters: Parameters,
+    registration: &Arc<R>,
+    checkpoint: &Option<Arc<Checkpoint>>,
+    tracker: &Option<Arc<Tracker>>,
+) -> Vec<(Cell, Result<Velocity, Failure>)> {
+    use std::thread;
+
+    let scheduler = Arc::new(Scheduler::new(cells, num_threads, Cell::cost));
+    let mut handles = Vec::new();
+    for i in 0..num_threads {
+        let scheduler = scheduler.clone();
+        let clouds = clouds.clone();
+        let registration = registration.clone();
+        let checkpoint = checkpoint.clone();
+        let tracker = tracker.clone();
+        let handle = thread::spawn(move || {
+            let worker = Worker { id: i };
+            worker.start(scheduler, clouds, parameters, registration, checkpoint, tracker)
+        });
+        handles.push(handle);
+    }
+    let mut velocities = Vec::new();
+    for (i, handle) in handles.into_iter().enumerate() {
+        match handle.join() {
+            Ok(v) => velocities.extend(v),
+            Err(_) => warn!("#{} panicked outside of a cell, its results are lost", i),
+        }
+    }
+    velocities
+}
+
+/// Orders addresses by their root's row and column, and then by their position in the root.
+fn sort_key(address: Address) -> (Index, Address) {
+    (address.root_index(), address)
+}
+
+/// Returns the message of a panic, if it has one.
+fn panic_message(payload: Box<Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => {
+            payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .unwrap_or_else(|| "panic without a message".to_string())
+        }
+    }
+}
+
+/// Creates a random number generator for a cell, so each cell's resamples don't depend on the
+/// order in which cells are processed.
+fn rng(seed: u64, address: Address) -> XorShiftRng {
+    let mut state = seed ^ (address.row as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^
+        (address.col as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F) ^
+        (address.level as u64).wrapping_mul(0x1656_67B1_9E37_79F9);
+    let mut words = [0u32; 4];
+    for word in words.iter_mut() {
+        state = splitmix64(state);
+        *word = state as u32;
+    }
+    words[0] |= 1;
+    XorShiftRng::from_seed(words)
+}
+
+fn splitmix64(state: u64) -> u64 {
+    let mut z = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Returns the indices of the square block of base grid cells with this lower-left corner.
+fn block(anchor: Index, span: i64) -> Vec<Index> {
+    let mut indices = Vec::new();
+    for row in anchor.row..(anchor.row + span) {
+        for col in anchor.col..(anchor.col + span) {
+            indices.push(Index { row: row, col: col });
+        }
+    }
+    indices
+}
+
+/// Scales an index's offset from an anchor by a span.
+fn scale(anchor: Index, index: Index, span: i64) -> Index {
+    Index {
+        row: anchor.row + (index.row - anchor.row) * span,
+        col: anchor.col + (index.col - anchor.col) * span,
+    }
+}
+
+/// Returns true if a block with these point counts is a better growth candidate than another.
+fn is_better_block(counts: (usize, usize), other: (usize, usize), min_points: usize) -> bool {
+    let limiting = counts.0.min(counts.1);
+    let other_limiting = other.0.min(other.1);
+    match (limiting >= min_points, other_limiting >= min_points) {
+        (true, true) => counts.0 + counts.1 < other.0 + other.1,
+        (true, false) => true,
+        (false, true) => false,
+        (false, false) => limiting > other_limiting,
+    }
+}
+
+fn matrix(points: &[Point], indices: &[usize]) -> Points {
+    let mut matrix = Points::zeros(indices.len());
+    for (i, &index) in indices.iter().enumerate() {
+        let point = &points[index];
+        matrix[(i, 0)] = point.x;
+        matrix[(i, 1)] = point.y;
+        matrix[(i, 2)] = point.z;
+    }
+    matrix
+}
+
+fn resample<R: Rng>(points: &Points, rng: &mut R) -> Points {
+    let n = points.nrows();
+    let mut resampled = Points::zeros(n);
+    for i in 0..n {
+        let j = rng.gen_range(0, n);
+        for d in 0..3 {
+            resampled[(i, d)] = points[(j, d)];
+        }
+    }
+    resampled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn legacy(x: i64, y: i64, grid_size: i64) -> serde_json::Value {
+        json!({
+            "after_points": 300,
+            "before_points": 300,
+            "center_of_gravity": {"x": 0., "y": 0., "z": 0.},
+            "datetime": "2017-06-01T12:00:00Z",
+            "grid_size": grid_size,
+            "iterations": 10,
+            "velocity": {"x": 0., "y": 0., "z": 0.},
+            "x": x,
+            "y": y
+        })
+    }
+
+    #[test]
+    fn migrate_grown_cell() {
+        // Legacy files stored (row, col) times the grid size as (x, y).
+        let velocities = serde_json::from_value(json!([
+            legacy(3 * 100, 2 * 100, 100),
+            legacy(4 * 200, -6 * 200, 200)
+        ])).unwrap();
+        let mut velocity_file = VelocityFile::from_legacy(velocities).unwrap();
+        velocity_file.migrate();
+        assert_eq!(Some(0), velocity_file.migrated_from);
+        assert_eq!(100., velocity_file.geometry.cell_size);
+
+        let base = &velocity_file.velocities[0];
+        assert_eq!((200., 300.), (base.x, base.y));
+        assert_eq!((0, 3, 2), (base.level, base.row, base.col));
+        assert_eq!(Bounds::new(200., 300., 100.), base.bounds);
+        assert!(base.members.is_empty());
+
+        let grown = &velocity_file.velocities[1];
+        assert_eq!((-600., 400.), (grown.x, grown.y));
+        assert_eq!((-1, 4, -6), (grown.level, grown.row, grown.col));
+        assert_eq!(Bounds::new(-600., 400., 200.), grown.bounds);
+        assert_eq!(4, grown.members.len());
+        assert!(grown.members.contains(&Index { row: 5, col: -5 }));
+    }
+}